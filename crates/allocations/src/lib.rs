@@ -5,9 +5,72 @@ use std::ffi::c_void;
 use windows::Win32::System::{Memory, SystemInformation};
 
 #[cfg(unix)]
-use libc::{mmap, munmap, MAP_ANON, MAP_PRIVATE, PROT_READ, PROT_WRITE};
-#[cfg(unix)]
+use libc::{mmap, munmap, MAP_ANON, MAP_FAILED, MAP_PRIVATE, PROT_READ, PROT_WRITE};
 use std::ptr::null_mut;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the page size of the system, which is the alignment [`allocate`] guarantees.
+pub fn page_size() -> usize {
+    let cached = PAGE_SIZE.load(Ordering::Relaxed);
+    if cached != 0 {
+        return cached;
+    }
+    let size = query_page_size();
+    PAGE_SIZE.store(size, Ordering::Relaxed);
+    size
+}
+
+#[cfg(unix)]
+fn query_page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+#[cfg(windows)]
+fn query_page_size() -> usize {
+    let mut info = SystemInformation::SYSTEM_INFO::default();
+    unsafe { SystemInformation::GetSystemInfo(&mut info) };
+    info.dwPageSize as usize
+}
+
+/// Maps `size` bytes aligned to `align`, which has to be a power of two. Alignments up to the page
+/// size are what [`allocate`] provides anyway, larger ones are served by mapping more than
+/// necessary and cutting off the unaligned rest.
+/// Returns null if the allocation failed.
+#[cfg(unix)]
+pub fn allocate_aligned(size: size_t, align: usize) -> *mut c_void {
+    let page = page_size();
+    if align <= page {
+        return allocate(size);
+    }
+    let size = size.next_multiple_of(page);
+    let raw = allocate(size + align - page);
+    if raw.is_null() {
+        return null_mut();
+    }
+    let aligned = (raw as usize).next_multiple_of(align);
+    let head = aligned - raw as usize;
+    let tail = align - page - head;
+    unsafe {
+        if head > 0 {
+            munmap(raw, head);
+        }
+        if tail > 0 {
+            munmap((aligned + size) as *mut c_void, tail);
+        }
+    }
+    aligned as *mut c_void
+}
+
+/// # Safety
+/// ptr and size must be the exact pointer and size of an allocation made by [`allocate_aligned`]
+/// with the same alignment. ptr is dangling afterwards.
+#[cfg(unix)]
+pub unsafe fn deallocate_aligned(ptr: *mut c_void, size: size_t, _align: usize) -> i32 {
+    // The unaligned rest was already cut off, so this is an ordinary mapping
+    munmap(ptr, size)
+}
 
 #[cfg(unix)]
 pub fn allocate(size: size_t) -> *mut c_void {
@@ -17,14 +80,20 @@ pub fn allocate(size: size_t) -> *mut c_void {
         // MAP_PRIVATE makes a copy-on-write mapping, where updates to the mapping are not visible to other processes.
         // MAP_ANON means it is not backed by a file, so fd is ignored, however some implementations want it to be -1 so it's -1
         // Offset is 0.
-        mmap(
+        let address = mmap(
             null_mut(),
             size,
             PROT_READ | PROT_WRITE,
             MAP_PRIVATE | MAP_ANON,
             -1,
             0,
-        )
+        );
+        // In an allocator we shall return zero if the allocation failed, mmap returns MAP_FAILED instead
+        if address == MAP_FAILED {
+            null_mut()
+        } else {
+            address
+        }
     }
 }
 /// # Safety
@@ -35,6 +104,52 @@ pub unsafe fn deallocate(ptr: *mut c_void, size: size_t) -> i32 {
     munmap(ptr, size)
 }
 
+/// Makes `size` bytes at `ptr` inaccessible, so any access to them faults. Returns whether the
+/// protection could be changed.
+///
+/// # Safety
+/// ptr must be page aligned and the range must lie within a mapping made by [`allocate`] or
+/// [`allocate_aligned`], which nothing uses anymore.
+#[cfg(unix)]
+pub unsafe fn protect(ptr: *mut c_void, size: size_t) -> bool {
+    unsafe { libc::mprotect(ptr, size, libc::PROT_NONE) == 0 }
+}
+
+/// Gives the pages of `size` bytes at `ptr` back to the OS, but keeps them mapped. They read as
+/// zero when they are touched again. Returns whether the pages could be given back, the contents
+/// are left alone otherwise.
+///
+/// # Safety
+/// ptr must be page aligned and the range must lie within a mapping made by [`allocate`] or
+/// [`allocate_aligned`], whose contents are not needed anymore.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub unsafe fn purge(ptr: *mut c_void, size: size_t) -> bool {
+    unsafe { libc::madvise(ptr, size, libc::MADV_DONTNEED) == 0 }
+}
+
+/// Gives the pages of `size` bytes at `ptr` back to the OS, but keeps them mapped. They read as
+/// zero when they are touched again. Returns whether the pages could be given back, the contents
+/// are left alone otherwise.
+///
+/// # Safety
+/// ptr must be page aligned and the range must lie within a mapping made by [`allocate`] or
+/// [`allocate_aligned`], whose contents are not needed anymore.
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+pub unsafe fn purge(ptr: *mut c_void, size: size_t) -> bool {
+    // MADV_DONTNEED doesn't zero the pages everywhere, mapping fresh ones over them does
+    let address = unsafe {
+        mmap(
+            ptr,
+            size,
+            PROT_READ | PROT_WRITE,
+            MAP_PRIVATE | MAP_ANON | libc::MAP_FIXED,
+            -1,
+            0,
+        )
+    };
+    address != MAP_FAILED
+}
+
 #[cfg(windows)]
 pub fn allocate(size: usize) -> *mut c_void {
     unsafe {
@@ -47,6 +162,50 @@ pub fn allocate(size: usize) -> *mut c_void {
     }
 }
 
+/// Maps `size` bytes aligned to `align`, which has to be a power of two.
+/// Returns null if the allocation failed.
+#[cfg(windows)]
+pub fn allocate_aligned(size: usize, align: usize) -> *mut c_void {
+    if align <= page_size() {
+        return allocate(size);
+    }
+    unsafe {
+        loop {
+            // VirtualFree can't release a part of a mapping, so find an aligned address in a larger
+            // reservation, give it back and map exactly at the aligned address.
+            let probe = Memory::VirtualAlloc(
+                None,
+                size + align,
+                Memory::MEM_RESERVE,
+                Memory::PAGE_NOACCESS,
+            );
+            if probe.is_null() {
+                return null_mut();
+            }
+            let aligned = (probe as usize).next_multiple_of(align) as *const c_void;
+            let _ = Memory::VirtualFree(probe, 0, Memory::MEM_RELEASE);
+            let address = Memory::VirtualAlloc(
+                Some(aligned),
+                size,
+                Memory::MEM_RESERVE | Memory::MEM_COMMIT,
+                Memory::PAGE_READWRITE,
+            );
+            // Another thread may have mapped the range in the meantime, in which case we try again
+            if !address.is_null() {
+                return address;
+            }
+        }
+    }
+}
+
+/// # Safety
+/// ptr and size must be the exact pointer and size of an allocation made by [`allocate_aligned`]
+/// with the same alignment. ptr is dangling afterwards.
+#[cfg(windows)]
+pub unsafe fn deallocate_aligned(ptr: *mut c_void, size: size_t, _align: usize) -> i32 {
+    deallocate(ptr, size)
+}
+
 /// # Safety
 /// ptr should be a valid pointer into a program allocated structure. size+ptr should never be larger than the allocation bound.
 /// Furthermore, ptr should no longer be stored as it is a dangling pointer after deallocation and using it is Use-After-Free
@@ -62,9 +221,53 @@ pub unsafe fn deallocate(ptr: *mut c_void, size: size_t) -> i32 {
     }
 }
 
-#[cfg(unix)]
+/// Makes `size` bytes at `ptr` inaccessible, so any access to them faults. Returns whether the
+/// protection could be changed.
+///
+/// # Safety
+/// ptr must be page aligned and the range must lie within a mapping made by [`allocate`] or
+/// [`allocate_aligned`], which nothing uses anymore.
+#[cfg(windows)]
+pub unsafe fn protect(ptr: *mut c_void, size: size_t) -> bool {
+    let mut old = Memory::PAGE_PROTECTION_FLAGS::default();
+    unsafe { Memory::VirtualProtect(ptr, size, Memory::PAGE_NOACCESS, &mut old).is_ok() }
+}
+
+/// Gives the pages of `size` bytes at `ptr` back to the OS, but keeps them mapped. They read as
+/// zero when they are touched again. Returns whether the pages could be given back.
+///
+/// # Safety
+/// ptr must be page aligned and the range must lie within a mapping made by [`allocate`] or
+/// [`allocate_aligned`], whose contents are not needed anymore.
+#[cfg(windows)]
+pub unsafe fn purge(ptr: *mut c_void, size: size_t) -> bool {
+    unsafe {
+        // Decommitted pages are zero once they are committed again
+        Memory::VirtualFree(ptr, size, Memory::MEM_DECOMMIT).is_ok()
+            && !Memory::VirtualAlloc(
+                Some(ptr as *const c_void),
+                size,
+                Memory::MEM_COMMIT,
+                Memory::PAGE_READWRITE,
+            )
+            .is_null()
+    }
+}
+
+/// Grows or shrinks a mapping, moving it if it can't be resized in place. Returns null on failure,
+/// in which case the old mapping is left untouched.
+///
+/// # Safety
+/// ptr must be the start of a mapping returned by [`allocate`] that is `old_size` bytes long.
+/// If the mapping was moved, ptr is dangling afterwards.
+#[cfg(target_os = "linux")]
 pub unsafe fn realloc(ptr: *mut c_void, old_size: size_t, new_size: size_t) -> *mut c_void {
     use libc::MREMAP_MAYMOVE;
 
-    unsafe { libc::mremap(ptr, old_size, new_size, MREMAP_MAYMOVE) }
+    let address = unsafe { libc::mremap(ptr, old_size, new_size, MREMAP_MAYMOVE) };
+    if address == MAP_FAILED {
+        null_mut()
+    } else {
+        address
+    }
 }
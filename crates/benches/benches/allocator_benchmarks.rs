@@ -1,5 +1,8 @@
 use benemalloc::BeneAlloc;
-use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use criterion::{
+    black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput,
+};
+use mimalloc::MiMalloc;
 use rand::prelude::*;
 use rand::rngs::SmallRng;
 use std::alloc::{GlobalAlloc, Layout};
@@ -344,6 +347,60 @@ fn bench_realistic_workload(c: &mut Criterion) {
     group.finish();
 }
 
+// Frees only, of blocks allocated beforehand. benemalloc finds the bin of a block from the layout
+// Rust passes to dealloc, mimalloc's free only gets the pointer and has to look up its page.
+// One batch fits into the thread cache, so every free takes the sized path.
+fn bench_sized_free(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sized_free");
+    const COUNT: usize = 256;
+    group.throughput(Throughput::Elements(COUNT as u64));
+
+    let mut rng = SmallRng::seed_from_u64(42);
+    let mixed: Vec<Layout> = (0..COUNT)
+        .map(|_| layout(rng.gen_range(8..=1024), 8))
+        .collect();
+    let patterns = [("64_bytes", vec![layout(64, 8); COUNT]), ("mixed", mixed)];
+
+    for (name, layouts) in &patterns {
+        group.bench_with_input(
+            BenchmarkId::new("bene_alloc", name),
+            layouts,
+            |b, layouts| {
+                b.iter_batched(
+                    || allocate_all(&BENE_ALLOC, layouts),
+                    |blocks| {
+                        for (ptr, layout) in blocks {
+                            unsafe { BENE_ALLOC.dealloc(ptr, layout) };
+                        }
+                    },
+                    BatchSize::PerIteration,
+                );
+            },
+        );
+
+        group.bench_with_input(BenchmarkId::new("mimalloc", name), layouts, |b, layouts| {
+            b.iter_batched(
+                || allocate_all(&MiMalloc, layouts),
+                |blocks| {
+                    for (ptr, layout) in blocks {
+                        unsafe { MiMalloc.dealloc(ptr, layout) };
+                    }
+                },
+                BatchSize::PerIteration,
+            );
+        });
+    }
+
+    group.finish();
+}
+
+fn allocate_all<A: GlobalAlloc>(allocator: &A, layouts: &[Layout]) -> Vec<(*mut u8, Layout)> {
+    layouts
+        .iter()
+        .map(|&layout| (unsafe { allocator.alloc(layout) }, layout))
+        .collect()
+}
+
 criterion_group!(
     benches,
     bench_basic_allocation,
@@ -352,7 +409,8 @@ criterion_group!(
     bench_mixed_pattern,
     bench_alignment,
     bench_fragmentation,
-    bench_realistic_workload
+    bench_realistic_workload,
+    bench_sized_free
 );
 
 criterion_main!(benches);
@@ -0,0 +1,122 @@
+//! The unstable [`Allocator`] trait, with the `nightly` feature, so collections can allocate
+//! through a benemalloc allocator or a [`Heap`] with `Vec::new_in` and `Box::new_in`.
+//!
+//! An allocator allocates from the heap of the current thread like the global one, its options
+//! only change what happens to its own blocks. Only allocators that never unmap memory keep it
+//! apart from the others. A [`Heap`] is separate from everything else and frees all at once.
+//!
+//! Allocations report the whole block that serves them, so a `Vec` gets the rest of its size
+//! class as capacity for free.
+
+use crate::{BeneAlloc, Heap};
+use std::alloc::{AllocError, Allocator, GlobalAlloc, Layout};
+use std::ptr::{NonNull, without_provenance_mut};
+
+// Zero-sized allocations don't touch the heap, they get a dangling pointer of their alignment
+fn dangling(layout: Layout) -> NonNull<[u8]> {
+    let ptr = unsafe { NonNull::new_unchecked(without_provenance_mut(layout.align())) };
+    NonNull::slice_from_raw_parts(ptr, 0)
+}
+
+fn block(ptr: *mut u8, len: usize) -> Result<NonNull<[u8]>, AllocError> {
+    let ptr = NonNull::new(ptr).ok_or(AllocError)?;
+    Ok(NonNull::slice_from_raw_parts(ptr, len))
+}
+
+// Both implement GlobalAlloc and usable_size, the trait is built on top of those
+macro_rules! impl_allocator {
+    ($allocator:ty) => {
+        unsafe impl Allocator for $allocator {
+            fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+                if layout.size() == 0 {
+                    return Ok(dangling(layout));
+                }
+                block(unsafe { self.alloc(layout) }, Self::usable_size(layout))
+            }
+
+            /// Only the requested size is zeroed and reported.
+            fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+                if layout.size() == 0 {
+                    return Ok(dangling(layout));
+                }
+                block(unsafe { self.alloc_zeroed(layout) }, layout.size())
+            }
+
+            unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+                if layout.size() != 0 {
+                    unsafe { self.dealloc(ptr.as_ptr(), layout) };
+                }
+            }
+
+            unsafe fn grow(
+                &self,
+                ptr: NonNull<u8>,
+                old_layout: Layout,
+                new_layout: Layout,
+            ) -> Result<NonNull<[u8]>, AllocError> {
+                unsafe { self.resize(ptr, old_layout, new_layout) }
+            }
+
+            unsafe fn grow_zeroed(
+                &self,
+                ptr: NonNull<u8>,
+                old_layout: Layout,
+                new_layout: Layout,
+            ) -> Result<NonNull<[u8]>, AllocError> {
+                let new = unsafe { self.resize(ptr, old_layout, new_layout)? };
+                let start = new.cast::<u8>().as_ptr();
+                unsafe {
+                    start
+                        .add(old_layout.size())
+                        .write_bytes(0, new_layout.size() - old_layout.size())
+                };
+                Ok(NonNull::slice_from_raw_parts(new.cast(), new_layout.size()))
+            }
+
+            unsafe fn shrink(
+                &self,
+                ptr: NonNull<u8>,
+                old_layout: Layout,
+                new_layout: Layout,
+            ) -> Result<NonNull<[u8]>, AllocError> {
+                unsafe { self.resize(ptr, old_layout, new_layout) }
+            }
+        }
+
+        impl $allocator {
+            /// Grows or shrinks a block through `realloc`, which resizes in place where it can.
+            ///
+            /// # Safety
+            /// ptr must be allocated by this allocator with `old_layout`.
+            unsafe fn resize(
+                &self,
+                ptr: NonNull<u8>,
+                old_layout: Layout,
+                new_layout: Layout,
+            ) -> Result<NonNull<[u8]>, AllocError> {
+                if old_layout.size() == 0 {
+                    return self.allocate(new_layout);
+                }
+                if new_layout.size() == 0 {
+                    unsafe { self.dealloc(ptr.as_ptr(), old_layout) };
+                    return Ok(dangling(new_layout));
+                }
+                if old_layout.align() == new_layout.align() {
+                    let new = unsafe { self.realloc(ptr.as_ptr(), old_layout, new_layout.size()) };
+                    return block(new, Self::usable_size(new_layout));
+                }
+                // realloc keeps the alignment, so the block has to move
+                let new = self.allocate(new_layout)?;
+                unsafe {
+                    let len = old_layout.size().min(new_layout.size());
+                    std::ptr::copy_nonoverlapping(ptr.as_ptr(), new.cast::<u8>().as_ptr(), len);
+                    self.dealloc(ptr.as_ptr(), old_layout);
+                }
+                Ok(new)
+            }
+        }
+    };
+}
+
+impl_allocator!(BeneAlloc);
+impl_allocator!(Heap);
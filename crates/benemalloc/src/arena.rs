@@ -0,0 +1,49 @@
+//! Bump allocation over large reserved regions, for allocators that never unmap memory.
+//!
+//! Reserving a region costs a single mapping, after that handing out memory is a pointer bump.
+//! Nothing is ever given back to the OS, memory is released when the process exits.
+
+use crate::PAGE_SIZE;
+use crate::spin::SpinLock;
+use crate::{env, stats};
+use allocations::{allocate, allocate_aligned};
+use std::ptr::null_mut;
+
+// The unused part of the current region
+struct Region {
+    cursor: usize,
+    end: usize,
+}
+
+static REGION: SpinLock<Region> = SpinLock::new(Region { cursor: 0, end: 0 });
+
+/// Returns `size` bytes aligned to `align`, or null if the OS is out of memory.
+/// The memory is always page aligned and zero, as it was never handed out before.
+pub(crate) fn bump(size: usize, align: usize) -> *mut u8 {
+    // The size of a reserved region, the kernel only backs the pages that are touched
+    let region_size = env::options().arena_reserve.max(PAGE_SIZE);
+    // Requests larger than this get a region of their own, so at most this much of a region is
+    // wasted when the next one is reserved
+    let max_bump = region_size / 8;
+    let align = align.max(PAGE_SIZE);
+    if size > max_bump || align > max_bump {
+        let ptr = allocate_aligned(size, align) as *mut u8;
+        if !ptr.is_null() {
+            stats::mapped(size);
+        }
+        return ptr;
+    }
+    let mut region = REGION.lock();
+    let mut start = region.cursor.next_multiple_of(align);
+    if region.cursor == 0 || start + size > region.end {
+        let base = allocate(region_size) as usize;
+        if base == 0 {
+            return null_mut();
+        }
+        stats::mapped(region_size);
+        region.end = base + region_size;
+        start = base.next_multiple_of(align);
+    }
+    region.cursor = start + size;
+    start as *mut u8
+}
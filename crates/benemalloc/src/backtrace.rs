@@ -0,0 +1,168 @@
+//! Captures the return addresses of the current stack without allocating, with the unwinder of
+//! the platform. There is none to link against on Windows, where nothing is captured.
+
+/// Fills `frames` with the innermost return addresses of the current stack and returns how many
+/// there were.
+#[cfg(unix)]
+pub(crate) fn capture(frames: &mut [usize]) -> usize {
+    use std::ffi::c_void;
+
+    #[repr(C)]
+    struct Context {
+        _private: [u8; 0],
+    }
+
+    // _URC_NO_REASON keeps the unwinder going, anything else stops it
+    const CONTINUE: i32 = 0;
+    const STOP: i32 = 5;
+
+    unsafe extern "C" {
+        fn _Unwind_Backtrace(
+            trace: extern "C" fn(*mut Context, *mut c_void) -> i32,
+            data: *mut c_void,
+        ) -> i32;
+        fn _Unwind_GetIP(context: *mut Context) -> usize;
+    }
+
+    struct Frames<'a> {
+        frames: &'a mut [usize],
+        len: usize,
+    }
+
+    extern "C" fn trace(context: *mut Context, data: *mut c_void) -> i32 {
+        let frames = unsafe { &mut *(data as *mut Frames) };
+        if frames.len == frames.frames.len() {
+            return STOP;
+        }
+        let ip = unsafe { _Unwind_GetIP(context) };
+        if ip == 0 {
+            return STOP;
+        }
+        frames.frames[frames.len] = ip;
+        frames.len += 1;
+        CONTINUE
+    }
+
+    let mut data = Frames { frames, len: 0 };
+    unsafe { _Unwind_Backtrace(trace, &mut data as *mut Frames as *mut c_void) };
+    data.len
+}
+
+#[cfg(not(unix))]
+pub(crate) fn capture(_frames: &mut [usize]) -> usize {
+    0
+}
+
+/// Hashes return addresses with FNV-1a, so the same call stack always has the same hash.
+pub(crate) fn hash(frames: &[usize]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for &frame in frames {
+        for byte in (frame as u64).to_le_bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+/// The most frames of a stack that are kept.
+#[cfg(any(feature = "heap_profile", feature = "leak_check"))]
+pub(crate) const MAX_FRAMES: usize = 64;
+/// The most distinct stacks that are kept.
+#[cfg(any(feature = "heap_profile", feature = "leak_check"))]
+pub(crate) const MAX_STACKS: usize = 1 << 12;
+
+#[cfg(any(feature = "heap_profile", feature = "leak_check"))]
+#[derive(Copy, Clone)]
+pub(crate) struct Stack {
+    // Zero for free entries
+    depth: usize,
+    hash: u64,
+    frames: [usize; MAX_FRAMES],
+}
+
+#[cfg(any(feature = "heap_profile", feature = "leak_check"))]
+impl Stack {
+    pub(crate) fn frames(&self) -> &[usize] {
+        &self.frames[..self.depth]
+    }
+}
+
+/// Every distinct stack once, so records only need its index. The table has a fixed size and is
+/// mapped on the first insert, so it doesn't come from the heap.
+#[cfg(any(feature = "heap_profile", feature = "leak_check"))]
+pub(crate) struct Stacks {
+    table: *mut Stack,
+}
+
+#[cfg(any(feature = "heap_profile", feature = "leak_check"))]
+unsafe impl Send for Stacks {}
+
+#[cfg(any(feature = "heap_profile", feature = "leak_check"))]
+impl Stacks {
+    pub(crate) const fn new() -> Self {
+        Self {
+            table: std::ptr::null_mut(),
+        }
+    }
+
+    fn table(&self) -> &[Stack] {
+        if self.table.is_null() {
+            return &[];
+        }
+        // Mapped memory is zero, which are free entries
+        unsafe { std::slice::from_raw_parts(self.table, MAX_STACKS) }
+    }
+
+    fn table_mut(&mut self) -> &mut [Stack] {
+        if self.table.is_null() {
+            let bytes = MAX_STACKS * size_of::<Stack>();
+            self.table = allocations::allocate(bytes) as *mut Stack;
+            if self.table.is_null() {
+                return &mut [];
+            }
+            crate::stats::mapped(bytes);
+        }
+        unsafe { std::slice::from_raw_parts_mut(self.table, MAX_STACKS) }
+    }
+
+    /// Returns the index of the stack, adding it if it is new, or `None` if the table is full.
+    pub(crate) fn intern(&mut self, frames: &[usize]) -> Option<usize> {
+        let frames = &frames[..frames.len().min(MAX_FRAMES)];
+        let hash = hash(frames);
+        let table = self.table_mut();
+        if table.is_empty() || frames.is_empty() {
+            return None;
+        }
+        let mut index = hash as usize % MAX_STACKS;
+        for _ in 0..MAX_STACKS {
+            let stack = &mut table[index];
+            if stack.depth == 0 {
+                stack.depth = frames.len();
+                stack.hash = hash;
+                stack.frames[..frames.len()].copy_from_slice(frames);
+                return Some(index);
+            }
+            if stack.hash == hash && stack.frames() == frames {
+                return Some(index);
+            }
+            index = (index + 1) % MAX_STACKS;
+        }
+        None
+    }
+
+    #[cfg_attr(not(feature = "leak_check"), allow(dead_code))]
+    pub(crate) fn get(&self, index: usize) -> Option<&Stack> {
+        self.table().get(index).filter(|stack| stack.depth != 0)
+    }
+
+    /// Calls `f` with the index of every stack and the stack.
+    #[cfg_attr(not(feature = "heap_profile"), allow(dead_code))]
+    pub(crate) fn for_each(&self, mut f: impl FnMut(usize, &Stack)) {
+        for (index, stack) in self.table().iter().enumerate() {
+            if stack.depth != 0 {
+                f(index, stack);
+            }
+        }
+    }
+}
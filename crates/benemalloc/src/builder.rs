@@ -0,0 +1,111 @@
+//! Compile time configuration of the allocator.
+
+use crate::BeneAlloc;
+use crate::size_class::MAX_BINNED_SIZE;
+
+/// Configures a [`BeneAlloc`]. Every method is const, so the allocator can be built right in the
+/// `#[global_allocator]` static:
+///
+/// ```
+/// use benemalloc::BeneAlloc;
+///
+/// #[global_allocator]
+/// static ALLOCATOR: BeneAlloc = BeneAlloc::builder()
+///     .thread_cache_capacity(1024)
+///     .max_cached_block(1 << 20)
+///     .build();
+/// ```
+#[derive(Copy, Clone, Debug)]
+pub struct Builder {
+    pub(crate) thread_cache_capacity: usize,
+    pub(crate) max_cached_block: usize,
+    pub(crate) never_unmap: bool,
+    pub(crate) free_fill: FreeFill,
+    pub(crate) randomize_reuse: bool,
+    pub(crate) cache_decay: usize,
+}
+
+/// What freed blocks are overwritten with, see [`Builder::free_fill`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FreeFill {
+    /// Freed blocks keep their contents.
+    Keep,
+    /// Fills freed blocks with zeroes, so their old contents don't leak into new allocations.
+    Zero,
+    /// Fills freed blocks with `0xde`, which also makes reads of uninitialized memory stand out.
+    Poison,
+}
+
+impl Builder {
+    pub const fn new() -> Self {
+        Self {
+            thread_cache_capacity: 512,
+            max_cached_block: MAX_BINNED_SIZE,
+            never_unmap: false,
+            free_fill: FreeFill::Keep,
+            randomize_reuse: false,
+            cache_decay: 4096,
+        }
+    }
+
+    /// The number of freed blocks every thread keeps for reuse, over all size classes.
+    /// Defaults to 512.
+    pub const fn thread_cache_capacity(mut self, blocks: usize) -> Self {
+        self.thread_cache_capacity = blocks;
+        self
+    }
+
+    /// The largest block in bytes the thread caches keep. Larger blocks are given back to the OS
+    /// as soon as they are freed. Blocks of up to 16 KiB are carved from shared segments and
+    /// always cached. Defaults to 64 MiB, everything above that is never cached.
+    pub const fn max_cached_block(mut self, bytes: usize) -> Self {
+        self.max_cached_block = bytes;
+        self
+    }
+
+    /// Never gives memory back to the OS, it is only released when the process exits. Memory is
+    /// bumped from large reserved regions instead of being mapped block by block, which saves
+    /// the syscalls of short-lived programs. Freed blocks are still reused, except for blocks
+    /// above 64 MiB or aligned to more than a page, whose free is a no-op. Allocators with and
+    /// without it may be used side by side, even on one thread, their memory is kept apart.
+    /// Defaults to false.
+    pub const fn never_unmap(mut self, never_unmap: bool) -> Self {
+        self.never_unmap = never_unmap;
+        self
+    }
+
+    /// Overwrites every block when it is freed, so secrets don't linger in recycled memory.
+    /// Blocks that go back to the OS right away are left alone. Filling large cached blocks costs
+    /// as much as writing them. Defaults to [`FreeFill::Keep`].
+    pub const fn free_fill(mut self, fill: FreeFill) -> Self {
+        self.free_fill = fill;
+        self
+    }
+
+    /// Hands out a random one of the most recently freed blocks of a size class instead of the
+    /// last one, so where an allocation lands can't be predicted from what was freed before.
+    /// This makes use-after-free bugs harder to exploit. Defaults to false.
+    pub const fn randomize_reuse(mut self, randomize: bool) -> Self {
+        self.randomize_reuse = randomize;
+        self
+    }
+
+    /// Every this many allocations and frees, a thread gives back the cached blocks it didn't
+    /// reuse since the last time, and releases the empty segments it didn't allocate from. Memory
+    /// that was needed once, for a spike, thereby doesn't stay with the thread forever. `0` keeps
+    /// everything until [`trim`](crate::trim) is called. Defaults to 4096.
+    pub const fn cache_decay(mut self, operations: usize) -> Self {
+        self.cache_decay = operations;
+        self
+    }
+
+    pub const fn build(self) -> BeneAlloc {
+        BeneAlloc::with_config(self)
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
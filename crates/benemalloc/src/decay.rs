@@ -0,0 +1,154 @@
+//! Giving back memory a thread cached but doesn't use anymore.
+//!
+//! Time is counted in allocations and frees, so keeping track of it costs no syscalls. Every
+//! [`Builder::cache_decay`] of them a thread decays its heap: the cached blocks of a class that
+//! were not reused since the last decay go back to the slabs, or to the OS if they have a mapping
+//! of their own, and empty segments that nothing was taken from since are unmapped. A bin is a
+//! stack, so the blocks that weren't reused are exactly the ones below the fewest it held.
+//!
+//! A thread that doesn't allocate doesn't decay either. [`trim`] gives back everything at once,
+//! e.g. after a batch job.
+
+use crate::size_class::{NUM_CLASSES, class_size};
+use crate::slab::{self, NUM_SMALL_CLASSES};
+use crate::{BeneAlloc, Builder, CURRENT_THREAD_ALLOCATOR, FreeBlock, InternalState};
+use crate::{PAGE_SIZE, release};
+use allocations::{page_size, purge};
+use std::ffi::c_void;
+
+impl InternalState {
+    /// Counts an allocation or free, and decays the heap once enough of them happened.
+    #[inline]
+    pub(crate) fn tick(&mut self, config: &Builder) {
+        self.ticks += 1;
+        if self.ticks >= config.cache_decay && config.cache_decay != 0 {
+            self.decay();
+        }
+    }
+
+    /// Gives back the cached blocks that were not reused since the last decay, and the segments
+    /// that were empty since.
+    #[cold]
+    fn decay(&mut self) {
+        self.ticks = 0;
+        for class in 0..NUM_CLASSES {
+            let idle = self.bins.bins[class].low;
+            // Blocks bumped from the arena can't be unmapped, they stay for reuse
+            if idle > 0 && (class < NUM_SMALL_CLASSES || !self.slabs.never_unmap) {
+                self.evict(class, idle);
+            }
+            let bin = &mut self.bins.bins[class];
+            bin.low = bin.len;
+        }
+        self.slabs.decay();
+    }
+
+    /// Gives back up to `count` of the blocks of `class` that were cached the longest. Small
+    /// blocks go back to their segment, large ones to the OS.
+    pub(crate) fn evict(&mut self, class: usize, count: usize) {
+        let mut block = self.bins.detach_oldest(class, count);
+        if class < NUM_SMALL_CLASSES {
+            unsafe { self.slabs.give_back(class, block) };
+            return;
+        }
+        debug_assert!(!self.slabs.never_unmap);
+        while !block.is_null() {
+            unsafe {
+                let next = (*block).next();
+                release(block as *mut u8, class_size(class), PAGE_SIZE);
+                block = next;
+            }
+        }
+    }
+
+    /// Gives back everything the heap caches and every empty segment, including the ones exited
+    /// threads left behind. With `never_unmap` memory stays mapped, but the pages of empty
+    /// segments and cached large blocks are given back.
+    fn trim(&mut self) {
+        self.ticks = 0;
+        for class in 0..NUM_CLASSES {
+            if class < NUM_SMALL_CLASSES || !self.slabs.never_unmap {
+                self.evict(class, usize::MAX);
+            } else {
+                let mut block = self.bins.bins[class].head;
+                while !block.is_null() {
+                    unsafe {
+                        purge_block(block, class_size(class));
+                        block = (*block).next();
+                    }
+                }
+            }
+            let bin = &mut self.bins.bins[class];
+            bin.low = bin.len;
+        }
+        self.slabs.release_empty();
+        if !self.slabs.never_unmap {
+            // The blocks of exited threads are only bumped from the arena if memory is never
+            // unmapped
+            let mut orphans = self.orphans().lock();
+            for class in NUM_SMALL_CLASSES..NUM_CLASSES {
+                while let Some((block, _)) = orphans.take(class) {
+                    unsafe { release(block, class_size(class), PAGE_SIZE) };
+                }
+            }
+        }
+        slab::release_empty_global();
+        slab::release_abandoned();
+    }
+}
+
+/// Gives the pages of a cached block back to the OS, except the one holding the link. The rest
+/// of the block is cleared as well, so it is marked as zeroed for alloc_zeroed.
+///
+/// # Safety
+/// block must be a cached block of `size` bytes with a mapping of its own.
+unsafe fn purge_block(block: *mut FreeBlock, size: usize) {
+    let page = page_size();
+    let start = block as usize + size_of::<FreeBlock>();
+    let end = block as usize + size;
+    let (from, to) = (start.next_multiple_of(page), end & !(page - 1));
+    if from >= to {
+        return;
+    }
+    unsafe {
+        if !purge(from as *mut c_void, to - from) {
+            return;
+        }
+        (start as *mut u8).write_bytes(0, from - start);
+        (to as *mut u8).write_bytes(0, end - to);
+        FreeBlock::init(block, (*block).next(), true);
+    }
+}
+
+/// Gives back memory the current thread cached but doesn't use anymore.
+///
+/// Without `force` this decays the heap of the thread right away: the cached blocks that were not
+/// reused since the last decay and the segments that were empty since are given back, as if
+/// [`Builder::cache_decay`] allocations and frees had passed. With `force` it does what [`trim`]
+/// does.
+///
+/// Other threads keep their caches, they decay as they allocate or when they call this
+/// themselves.
+pub fn collect(force: bool) {
+    if !BeneAlloc::heap_usable() {
+        return;
+    }
+    let _ = CURRENT_THREAD_ALLOCATOR.try_with(|heaps| {
+        for state in unsafe { &mut *heaps.get() } {
+            if force {
+                state.trim();
+            } else {
+                state.decay();
+            }
+        }
+    });
+}
+
+/// Gives back all memory the current thread caches, the segments without slots in use and the
+/// segments and large blocks threads left behind when they exited, e.g. after a batch job.
+///
+/// With [`Builder::never_unmap`] nothing is unmapped, but the pages of empty segments and cached
+/// large blocks still go back to the OS. They are zero when they are used again.
+pub fn trim() {
+    collect(true);
+}
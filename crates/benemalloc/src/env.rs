@@ -0,0 +1,280 @@
+//! Runtime configuration through `BENEMALLOC_*` environment variables, in the style of
+//! `MIMALLOC_*` and `MALLOC_CONF`.
+//!
+//! The variables are read once, on the first allocation. std's accessors allocate, so they are
+//! read with `getenv` instead. Set variables override what the [`Builder`] configured:
+//!
+//! - `BENEMALLOC_CACHE_SIZE`: see [`Builder::thread_cache_capacity`]
+//! - `BENEMALLOC_MAX_CACHED_BLOCK`: see [`Builder::max_cached_block`]
+//! - `BENEMALLOC_NEVER_UNMAP`: see [`Builder::never_unmap`]
+//! - `BENEMALLOC_FREE_FILL`: `keep`, `zero` or `poison`, see [`Builder::free_fill`]
+//! - `BENEMALLOC_RANDOMIZE_REUSE`: see [`Builder::randomize_reuse`]
+//! - `BENEMALLOC_CACHE_DECAY`: see [`Builder::cache_decay`]
+//! - `BENEMALLOC_ARENA_RESERVE`: how much memory is reserved at once when memory is never
+//!   unmapped, 256 MiB by default
+//! - `BENEMALLOC_VERBOSE`: prints the configuration of every allocator and failures to stderr
+//! - `BENEMALLOC_TRACK`: the file the trace is written to with the `track_allocations` feature,
+//!   or a directory the trace is written to as `benemalloc-<pid>.trace`, which is also the
+//!   default in the working directory. Tracking is disabled if the trace can't be opened
+//! - `BENEMALLOC_TRACK_FD`: a file descriptor the trace is written to instead, e.g. a pipe
+//! - `BENEMALLOC_PROFILE_RATE`: the average bytes between samples of the heap profiler with the
+//!   `heap_profile` feature, 512 KiB by default, `0` turns it off
+//! - `BENEMALLOC_LEAK_REPORT`: whether the leaks are printed at exit with the `leak_check`
+//!   feature, on by default
+//! - `BENEMALLOC_LEAK_CALLSITE`: how many frames of the stack are recorded for every allocation
+//!   with the `leak_check` feature, 0 and off by default
+//! - `BENEMALLOC_TRACK_CALLSITE`: how many frames of the stack are hashed into the call site of
+//!   every traced event, 0 and off by default
+//!
+//! Sizes may have a `k`, `m` or `g` suffix. Switches are turned on by `1`, `true`, `yes` or `on`
+//! and off by `0`, `false`, `no` or `off`.
+
+use crate::builder::{Builder, FreeFill};
+use std::cell::UnsafeCell;
+use std::ffi::{CStr, c_char};
+use std::fmt::{self, Write};
+use std::hint::spin_loop;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+const UNLOADED: u8 = 0;
+const LOADING: u8 = 1;
+const LOADED: u8 = 2;
+
+static STATE: AtomicU8 = AtomicU8::new(UNLOADED);
+static OPTIONS: Loaded = Loaded(UnsafeCell::new(Options::new()));
+
+// Written once by the thread that loads the options, before STATE is set to LOADED
+struct Loaded(UnsafeCell<Options>);
+
+unsafe impl Sync for Loaded {}
+
+pub(crate) struct Options {
+    cache_size: Option<usize>,
+    max_cached_block: Option<usize>,
+    never_unmap: Option<bool>,
+    free_fill: Option<FreeFill>,
+    randomize_reuse: Option<bool>,
+    cache_decay: Option<usize>,
+    pub(crate) arena_reserve: usize,
+    pub(crate) verbose: bool,
+    /// Where the trace is written to, negative when tracking is disabled
+    #[cfg_attr(not(feature = "track_allocations"), allow(dead_code))]
+    pub(crate) track_fd: i32,
+    #[cfg_attr(not(feature = "track_allocations"), allow(dead_code))]
+    pub(crate) track_callsite: usize,
+    #[cfg_attr(not(feature = "heap_profile"), allow(dead_code))]
+    pub(crate) profile_rate: usize,
+    #[cfg_attr(not(feature = "leak_check"), allow(dead_code))]
+    pub(crate) leak_report: bool,
+    #[cfg_attr(not(feature = "leak_check"), allow(dead_code))]
+    pub(crate) leak_callsite: usize,
+}
+
+impl Options {
+    const fn new() -> Self {
+        Self {
+            cache_size: None,
+            max_cached_block: None,
+            never_unmap: None,
+            free_fill: None,
+            randomize_reuse: None,
+            cache_decay: None,
+            arena_reserve: 256 << 20,
+            verbose: false,
+            track_fd: libc::STDERR_FILENO,
+            track_callsite: 0,
+            profile_rate: 512 << 10,
+            leak_report: true,
+            leak_callsite: 0,
+        }
+    }
+
+    fn from_env() -> Self {
+        let mut options = Self::new();
+        options.cache_size = var(c"BENEMALLOC_CACHE_SIZE").and_then(parse_size);
+        options.max_cached_block = var(c"BENEMALLOC_MAX_CACHED_BLOCK").and_then(parse_size);
+        options.never_unmap = var(c"BENEMALLOC_NEVER_UNMAP").and_then(parse_switch);
+        options.free_fill = var(c"BENEMALLOC_FREE_FILL").and_then(parse_fill);
+        options.randomize_reuse = var(c"BENEMALLOC_RANDOMIZE_REUSE").and_then(parse_switch);
+        options.cache_decay = var(c"BENEMALLOC_CACHE_DECAY").and_then(parse_size);
+        if let Some(reserve) = var(c"BENEMALLOC_ARENA_RESERVE").and_then(parse_size) {
+            options.arena_reserve = reserve;
+        }
+        options.verbose = var(c"BENEMALLOC_VERBOSE")
+            .and_then(parse_switch)
+            .unwrap_or(false);
+        if let Some(rate) = var(c"BENEMALLOC_PROFILE_RATE").and_then(parse_size) {
+            options.profile_rate = rate;
+        }
+        if let Some(report) = var(c"BENEMALLOC_LEAK_REPORT").and_then(parse_switch) {
+            options.leak_report = report;
+        }
+        if let Some(frames) = var(c"BENEMALLOC_LEAK_CALLSITE").and_then(parse_size) {
+            options.leak_callsite = frames;
+        }
+        #[cfg(feature = "track_allocations")]
+        options.open_trace();
+        options
+    }
+
+    #[cfg(feature = "track_allocations")]
+    fn open_trace(&mut self) {
+        use crate::trace::Header;
+
+        self.track_callsite = var(c"BENEMALLOC_TRACK_CALLSITE")
+            .and_then(parse_size)
+            .unwrap_or(0);
+        if let Some(fd) = var(c"BENEMALLOC_TRACK_FD").and_then(|fd| fd.trim().parse().ok()) {
+            self.track_fd = fd;
+        } else {
+            // Large enough for the default name, longer paths have to be set explicitly
+            let mut name = [0u8; 64];
+            let mut cursor = std::io::Cursor::new(&mut name[..63]);
+            let _ = std::io::Write::write_fmt(
+                &mut cursor,
+                format_args!("benemalloc-{}.trace", std::process::id()),
+            );
+            let name = name.as_ptr() as *const c_char;
+            // Child processes don't inherit the trace, they open one of their own
+            let flags =
+                libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_APPEND | libc::O_CLOEXEC;
+            let fd = match var_raw(c"BENEMALLOC_TRACK") {
+                None => unsafe { libc::open(name, flags, 0o644) },
+                Some(path) => {
+                    // A directory gets a trace per process, so processes running at once don't
+                    // overwrite each other
+                    let dir = unsafe {
+                        libc::open(path, libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC)
+                    };
+                    if dir < 0 {
+                        unsafe { libc::open(path, flags, 0o644) }
+                    } else {
+                        let fd = unsafe { libc::openat(dir, name, flags, 0o644) };
+                        unsafe { libc::close(dir) };
+                        fd
+                    }
+                }
+            };
+            if fd < 0 {
+                if self.verbose {
+                    log(format_args!("cannot open the trace, tracking is disabled"));
+                }
+                self.track_fd = -1;
+                return;
+            }
+            self.track_fd = fd;
+        }
+        let header = Header::current().to_bytes();
+        unsafe { libc::write(self.track_fd, header.as_ptr() as _, header.len() as _) };
+    }
+
+    fn apply(&self, config: Builder) -> Builder {
+        Builder {
+            thread_cache_capacity: self.cache_size.unwrap_or(config.thread_cache_capacity),
+            max_cached_block: self.max_cached_block.unwrap_or(config.max_cached_block),
+            never_unmap: self.never_unmap.unwrap_or(config.never_unmap),
+            free_fill: self.free_fill.unwrap_or(config.free_fill),
+            randomize_reuse: self.randomize_reuse.unwrap_or(config.randomize_reuse),
+            cache_decay: self.cache_decay.unwrap_or(config.cache_decay),
+        }
+    }
+}
+
+/// Returns the options read from the environment.
+pub(crate) fn options() -> &'static Options {
+    if STATE.load(Ordering::Acquire) != LOADED {
+        load();
+    }
+    unsafe { &*OPTIONS.0.get() }
+}
+
+/// Returns `config` with the overrides from the environment applied. With `BENEMALLOC_VERBOSE`
+/// the result is printed, which is the configuration the allocator runs with.
+pub(crate) fn configure(config: Builder) -> Builder {
+    let options = options();
+    let config = options.apply(config);
+    if options.verbose {
+        log(format_args!(
+            "thread_cache_capacity={} max_cached_block={} never_unmap={} free_fill={:?} randomize_reuse={} cache_decay={} arena_reserve={}",
+            config.thread_cache_capacity,
+            config.max_cached_block,
+            config.never_unmap,
+            config.free_fill,
+            config.randomize_reuse,
+            config.cache_decay,
+            options.arena_reserve
+        ));
+    }
+    config
+}
+
+// Loading doesn't allocate, so no allocation can come back here while one thread loads.
+// Threads racing it just wait until it is done.
+#[cold]
+fn load() {
+    if STATE
+        .compare_exchange(UNLOADED, LOADING, Ordering::Acquire, Ordering::Acquire)
+        .is_err()
+    {
+        while STATE.load(Ordering::Acquire) != LOADED {
+            spin_loop();
+        }
+        return;
+    }
+    let options = Options::from_env();
+    unsafe { *OPTIONS.0.get() = options };
+    STATE.store(LOADED, Ordering::Release);
+}
+
+/// Prints a line to stderr without allocating.
+pub(crate) fn log(args: fmt::Arguments) {
+    let _ = writeln!(Stderr, "benemalloc: {args}");
+}
+
+struct Stderr;
+
+impl Write for Stderr {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        unsafe { libc::write(libc::STDERR_FILENO, s.as_ptr() as _, s.len() as _) };
+        Ok(())
+    }
+}
+
+fn var_raw(name: &CStr) -> Option<*const c_char> {
+    let value = unsafe { libc::getenv(name.as_ptr()) };
+    (!value.is_null()).then_some(value as *const c_char)
+}
+
+fn var(name: &CStr) -> Option<&'static str> {
+    let value = var_raw(name)?;
+    // The environment isn't modified while the allocator starts up
+    unsafe { CStr::from_ptr(value) }.to_str().ok()
+}
+
+fn parse_size(value: &str) -> Option<usize> {
+    let value = value.trim();
+    let (digits, shift) = match value.as_bytes().last()? {
+        b'k' | b'K' => (&value[..value.len() - 1], 10),
+        b'm' | b'M' => (&value[..value.len() - 1], 20),
+        b'g' | b'G' => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(1 << shift)
+}
+
+fn parse_fill(value: &str) -> Option<FreeFill> {
+    match value.trim() {
+        "keep" | "0" | "off" => Some(FreeFill::Keep),
+        "zero" => Some(FreeFill::Zero),
+        "poison" => Some(FreeFill::Poison),
+        _ => None,
+    }
+}
+
+fn parse_switch(value: &str) -> Option<bool> {
+    match value.trim() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
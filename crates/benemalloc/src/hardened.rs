@@ -0,0 +1,226 @@
+//! Checks that catch heap corruption where it happens, with the `hardened` feature.
+//!
+//! Blocks are recorded with their layout when they are allocated and marked when they are
+//! freed. Freeing a block twice, freeing a pointer this allocator never handed out or freeing
+//! with a layout the block wasn't allocated with would corrupt the heap, so the process is
+//! aborted with a diagnostic instead. Rust code doesn't do that, but C code linked into it can.
+//! The states live in a table outside the heap, which every allocation and free has to lock.
+//! Freed blocks are only remembered until their part of the table needs room, a double free of
+//! a block that was freed long ago is reported as a free of a pointer benemalloc didn't allocate.
+//!
+//! Writes past the end of a block are caught too, in the spirit of GrapheneOS's hardened_malloc.
+//! Blocks with a mapping of their own sit between two guard pages, and the last page of every
+//! slab segment is one, so running off them faults right away. Small objects are followed by
+//! canary bytes up to the end of their slot, which are checked when they are freed.
+//!
+//! A free may pass a larger size than was requested, as long as it is within
+//! [`BeneAlloc::usable_size`] of the allocation. Small objects have no room past their size,
+//! it belongs to the canary.
+
+use crate::size_class::block_size;
+use crate::slab::SMALL_MAX;
+use crate::table::Shards;
+use crate::{BeneAlloc, env};
+use allocations::{allocate_aligned, deallocate_aligned, page_size, protect};
+use std::alloc::Layout;
+use std::ffi::c_void;
+use std::ptr::null_mut;
+
+// At least this many canary bytes follow every small object
+const CANARY_SIZE: usize = 8;
+
+static BLOCKS: Shards<Block> = Shards::new();
+
+#[derive(Copy, Clone)]
+struct Block {
+    size: usize,
+    align: usize,
+    allocated: bool,
+}
+
+/// Records a block that was handed out.
+pub(crate) fn allocated(ptr: *mut u8, layout: Layout) {
+    if ptr.is_null() {
+        return;
+    }
+    let block = Block {
+        size: layout.size(),
+        align: layout.align(),
+        allocated: true,
+    };
+    if !BLOCKS
+        .shard(ptr as usize)
+        .lock()
+        .insert_evicting(ptr as usize, block, |block| !block.allocated)
+    {
+        // Without the record its free would look invalid
+        fail(format_args!(
+            "cannot record the allocation of {ptr:?}, out of memory"
+        ));
+    }
+    unsafe { write_canary(ptr, layout) };
+}
+
+/// Aborts unless `ptr` may be freed with `layout`, and marks the block as free.
+pub(crate) fn freed(ptr: *mut u8, layout: Layout) {
+    let mut blocks = BLOCKS.shard(ptr as usize).lock();
+    let block = check(blocks.get(ptr as usize), ptr, layout);
+    blocks.insert(
+        ptr as usize,
+        Block {
+            allocated: false,
+            ..block
+        },
+    );
+}
+
+/// Aborts unless `ptr` may be resized from `layout`.
+pub(crate) fn resizing(ptr: *mut u8, layout: Layout) {
+    check(
+        BLOCKS.shard(ptr as usize).lock().get(ptr as usize),
+        ptr,
+        layout,
+    );
+}
+
+/// Records that a block was resized in place or moved by the OS.
+pub(crate) fn resized(ptr: *mut u8, layout: Layout, new_ptr: *mut u8, new_layout: Layout) {
+    if new_ptr != ptr {
+        freed(ptr, layout);
+    }
+    allocated(new_ptr, new_layout);
+}
+
+fn check(block: Option<Block>, ptr: *mut u8, layout: Layout) -> Block {
+    let Some(block) = block else {
+        fail(format_args!(
+            "free of {ptr:?}, which was not allocated by benemalloc"
+        ));
+    };
+    if !block.allocated {
+        fail(format_args!(
+            "double free of {ptr:?}, a block of {} bytes",
+            block.size
+        ));
+    }
+    let allocation = unsafe { Layout::from_size_align_unchecked(block.size, block.align) };
+    if layout.align() != block.align
+        || layout.size() < block.size
+        || layout.size() > BeneAlloc::usable_size(allocation)
+    {
+        fail(format_args!(
+            "free of {ptr:?} with size {} and alignment {}, but it was allocated with size {} and alignment {}",
+            layout.size(),
+            layout.align(),
+            block.size,
+            block.align
+        ));
+    }
+    // The size matches now, so the canary starts right after it
+    if !unsafe { canary_intact(ptr, layout) } {
+        fail(format_args!(
+            "write past the end of {ptr:?}, a block of {} bytes, overwrote the canary after it",
+            block.size
+        ));
+    }
+    block
+}
+
+/// The layout of the block that serves `layout`. Small objects get room for a canary after them.
+pub(crate) const fn padded(layout: Layout) -> Layout {
+    if layout.size() < SMALL_MAX {
+        // Still far from overflowing
+        let padded = unsafe {
+            Layout::from_size_align_unchecked(layout.size() + CANARY_SIZE, layout.align())
+        };
+        if block_size(padded) <= SMALL_MAX {
+            return padded;
+        }
+    }
+    layout
+}
+
+/// Whether blocks of `layout` are followed by a canary.
+pub(crate) const fn has_canary(layout: Layout) -> bool {
+    padded(layout).size() != layout.size()
+}
+
+// Depends on the address of the block and, through ASLR, on the process, so overflowing code
+// can't simply write the value that was there
+fn canary(ptr: *mut u8) -> [u8; CANARY_SIZE] {
+    let secret = &BLOCKS as *const _ as u64;
+    let hash = (ptr as u64 ^ secret).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    // The low bits of the product only depend on the low bits of the address
+    (hash ^ (hash >> 29)).to_le_bytes()
+}
+
+/// # Safety
+/// ptr must be a block allocated with the padded `layout`.
+unsafe fn write_canary(ptr: *mut u8, layout: Layout) {
+    if !has_canary(layout) {
+        return;
+    }
+    let canary = canary(ptr);
+    for offset in layout.size()..block_size(padded(layout)) {
+        unsafe { ptr.add(offset).write(canary[offset % CANARY_SIZE]) };
+    }
+}
+
+/// # Safety
+/// ptr must be a block allocated with the padded `layout`.
+unsafe fn canary_intact(ptr: *mut u8, layout: Layout) -> bool {
+    if !has_canary(layout) {
+        return true;
+    }
+    let canary = canary(ptr);
+    (layout.size()..block_size(padded(layout)))
+        .all(|offset| unsafe { ptr.add(offset).read() } == canary[offset % CANARY_SIZE])
+}
+
+/// Maps a block of `size` bytes between two guard pages. The leading guard covers a whole
+/// alignment, so the block after it stays aligned. Returns null if the OS is out of memory.
+pub(crate) fn map_guarded(size: usize, align: usize) -> *mut u8 {
+    let page = page_size();
+    let lead = align.max(page);
+    let size = size.next_multiple_of(page);
+    let base = allocate_aligned(lead + size + page, align) as *mut u8;
+    if base.is_null() {
+        return null_mut();
+    }
+    unsafe {
+        let block = base.add(lead);
+        // Without the guards the block is still usable, just not protected
+        protect(base as *mut c_void, lead);
+        protect(block.add(size) as *mut c_void, page);
+        block
+    }
+}
+
+/// Unmaps a block mapped by [`map_guarded`] together with its guard pages.
+///
+/// # Safety
+/// ptr must be a block of `size` bytes mapped by [`map_guarded`] with `align`, or any alignment
+/// up to the page size if that is what it was mapped with. It is dangling afterwards.
+pub(crate) unsafe fn unmap_guarded(ptr: *mut u8, size: usize, align: usize) {
+    let page = page_size();
+    let lead = align.max(page);
+    let total = lead + size.next_multiple_of(page) + page;
+    unsafe { deallocate_aligned(ptr.sub(lead) as *mut c_void, total, align) };
+}
+
+/// Turns the last page of the `size` bytes at `base` into a guard page and returns how many bytes
+/// are left in front of it.
+///
+/// # Safety
+/// base must be a page aligned mapping of `size` bytes that nothing uses yet.
+pub(crate) unsafe fn guard_tail(base: *mut u8, size: usize) -> usize {
+    let page = page_size();
+    unsafe { protect(base.add(size - page) as *mut c_void, page) };
+    size - page
+}
+
+#[cold]
+fn fail(args: std::fmt::Arguments) -> ! {
+    env::log(args);
+    std::process::abort()
+}
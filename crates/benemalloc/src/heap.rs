@@ -0,0 +1,185 @@
+//! Heaps that are created and destroyed at runtime, apart from the heaps of the threads.
+//!
+//! A [`Heap`] carves its small blocks from segments only it uses and maps its large blocks
+//! itself. Destroying it gives all of that back to the OS at once, whether the blocks were freed
+//! or not, so memory that lives exactly as long as e.g. a request needs no frees one by one:
+//!
+//! ```
+//! use benemalloc::Heap;
+//! use std::alloc::{GlobalAlloc, Layout};
+//!
+//! let heap = Heap::new();
+//! let layout = Layout::new::<[u64; 8]>();
+//! for _ in 0..1000 {
+//!     let block = unsafe { heap.alloc(layout) };
+//!     assert!(!block.is_null());
+//! }
+//! heap.destroy();
+//! ```
+//!
+//! With the `nightly` feature a heap also implements the `Allocator` trait, so collections can
+//! live in it with `Vec::new_in(&heap)`.
+//!
+//! Blocks of a heap must be freed through the heap, or not at all. The debugging features don't
+//! see them, and the options of the [`Builder`](crate::Builder) don't apply to them.
+
+use crate::Zeroed;
+use crate::env;
+use crate::size_class::{block_size, class_for, class_size};
+use crate::slab::{NUM_SMALL_CLASSES, Slabs};
+use crate::stats;
+use crate::table::AddrMap;
+use allocations::{allocate_aligned, deallocate_aligned};
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::UnsafeCell;
+use std::ffi::c_void;
+use std::ptr::null_mut;
+
+/// A heap that frees all of its memory at once when it is dropped, like `mi_heap_destroy`.
+///
+/// A heap belongs to one thread at a time: it can be sent to another thread, but not shared.
+pub struct Heap {
+    inner: UnsafeCell<Inner>,
+}
+
+struct Inner {
+    slabs: Slabs,
+    // Blocks with a mapping of their own, by address
+    large: AddrMap<Large>,
+    // Slots handed out per class and not freed yet, they count as freed when the heap goes away
+    live: [usize; NUM_SMALL_CLASSES],
+}
+
+#[derive(Copy, Clone)]
+struct Large {
+    size: usize,
+    align: usize,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    /// Creates an empty heap. Memory is only mapped once something is allocated.
+    pub const fn new() -> Self {
+        Self {
+            inner: UnsafeCell::new(Inner {
+                slabs: Slabs::isolated(),
+                large: AddrMap::new(),
+                live: [0; NUM_SMALL_CLASSES],
+            }),
+        }
+    }
+
+    /// Returns how many bytes an allocation of `layout` really has, see
+    /// [`BeneAlloc::usable_size`](crate::BeneAlloc::usable_size).
+    pub const fn usable_size(layout: Layout) -> usize {
+        block_size(layout)
+    }
+
+    /// Gives all memory of the heap back to the OS, including the blocks that were not freed.
+    /// Dropping the heap does the same.
+    pub fn destroy(self) {}
+
+    /// Allocates a block and reports how much of it is known to be zero.
+    fn alloc_block(&self, layout: Layout) -> (*mut u8, Zeroed) {
+        // The heap is not Sync, and nothing calls back into it while this is alive
+        let inner = unsafe { &mut *self.inner.get() };
+        let class = class_for(layout);
+        if let Some(class) = class.filter(|&class| class < NUM_SMALL_CLASSES) {
+            let (ptr, zeroed) = inner.slabs.take_one(class);
+            if !ptr.is_null() {
+                inner.live[class] += 1;
+                stats::alloc_shared(Some(class), class_size(class));
+            }
+            return (ptr, zeroed);
+        }
+        let size = block_size(layout);
+        let ptr = allocate_aligned(size, layout.align()) as *mut u8;
+        if ptr.is_null() {
+            return (null_mut(), Zeroed::All);
+        }
+        let align = layout.align();
+        if !inner.large.insert(ptr as usize, Large { size, align }) {
+            unsafe { deallocate_aligned(ptr as *mut c_void, size, align) };
+            return (null_mut(), Zeroed::All);
+        }
+        stats::mapped(size);
+        stats::alloc_shared(class, size);
+        (ptr, Zeroed::All)
+    }
+}
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.alloc_block(layout).0
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let (ptr, zeroed) = self.alloc_block(layout);
+        if !ptr.is_null() {
+            unsafe { zeroed.clear(ptr, layout.size()) };
+        }
+        ptr
+    }
+
+    /// # Safety
+    /// ptr must have been allocated by this heap with the given layout.
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let inner = unsafe { &mut *self.inner.get() };
+        let class = class_for(layout);
+        if let Some(class) = class.filter(|&class| class < NUM_SMALL_CLASSES) {
+            inner.live[class] -= 1;
+            stats::free_shared(Some(class), class_size(class));
+            unsafe { inner.slabs.give_back_one(class, ptr) };
+            return;
+        }
+        let Some(block) = inner.large.remove(ptr as usize) else {
+            // Unmapping it would pull the memory out from under whoever owns it
+            env::log(format_args!("{ptr:?} is not a block of this heap"));
+            std::process::abort();
+        };
+        stats::free_shared(class, block.size);
+        unsafe { deallocate_aligned(ptr as *mut c_void, block.size, block.align) };
+        stats::unmapped(block.size);
+    }
+
+    /// Blocks have the size of their class, so a resize within the class is free.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // The trait guarantees that the new size, rounded up to the alignment, fits into an isize
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        if class_for(layout).is_some() && class_for(layout) == class_for(new_layout) {
+            return ptr;
+        }
+        let new_ptr = unsafe { self.alloc(new_layout) };
+        if !new_ptr.is_null() {
+            unsafe {
+                std::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+        }
+        new_ptr
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        let inner = self.inner.get_mut();
+        for (class, &live) in inner.live.iter().enumerate() {
+            if live != 0 {
+                stats::free_shared_many(Some(class), live, class_size(class));
+            }
+        }
+        inner.slabs.release_all();
+        inner.large.for_each(|addr, block| {
+            // Binned sizes are multiples of their alignment, so this is the class of the request
+            let layout = unsafe { Layout::from_size_align_unchecked(block.size, block.align) };
+            stats::free_shared(class_for(layout), block.size);
+            unsafe { deallocate_aligned(addr as *mut c_void, block.size, block.align) };
+            stats::unmapped(block.size);
+        });
+    }
+}
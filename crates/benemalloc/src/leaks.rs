@@ -0,0 +1,278 @@
+//! A leak checker, with the `leak_check` feature.
+//!
+//! Every allocation is recorded in a table outside the heap until it is freed.
+//! [`report_leaks`] prints what is still allocated, grouped by size and call site. That also
+//! happens at exit unless `BENEMALLOC_LEAK_REPORT=0` is set. The report includes what the
+//! standard library allocates once and never frees, like the buffer of stdout.
+//!
+//! Call sites are recorded with `BENEMALLOC_LEAK_CALLSITE=<frames>`, which makes every
+//! allocation capture its stack and is a lot slower.
+//!
+//! Tests can check that a piece of code frees everything it allocates with a [`Checkpoint`]:
+//!
+//! ```
+//! use benemalloc::BeneAlloc;
+//!
+//! #[global_allocator]
+//! static ALLOCATOR: BeneAlloc = BeneAlloc::new();
+//!
+//! let checkpoint = benemalloc::leaks::checkpoint();
+//! let request = vec![0u8; 100];
+//! drop(request);
+//! assert!(checkpoint.thread_leaks().is_empty());
+//! ```
+
+use crate::backtrace::{self, MAX_FRAMES, Stacks};
+use crate::env;
+use crate::spin::SpinLock;
+use crate::table::Shards;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+const NO_STACK: u32 = u32::MAX;
+
+static BLOCKS: Shards<Block> = Shards::new();
+static STACKS: SpinLock<Stacks> = SpinLock::new(Stacks::new());
+// Orders allocations, for checkpoints
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+static NEXT_THREAD: AtomicU32 = AtomicU32::new(1);
+static AT_EXIT: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static THREAD: Cell<u32> = const { Cell::new(0) };
+    // Set while the leak checker works on this thread, the allocations it makes are not
+    // recorded. Frees are, as they may concern blocks that were recorded before.
+    static BUSY: Cell<bool> = const { Cell::new(false) };
+}
+
+#[derive(Copy, Clone)]
+struct Block {
+    size: usize,
+    sequence: u64,
+    stack: u32,
+    thread: u32,
+}
+
+fn thread() -> u32 {
+    if THREAD.get() == 0 {
+        THREAD.set(NEXT_THREAD.fetch_add(1, Ordering::Relaxed));
+    }
+    THREAD.get()
+}
+
+/// Records an allocation.
+#[inline]
+pub(crate) fn allocated(ptr: *mut u8, size: usize) {
+    if !ptr.is_null() && !BUSY.get() {
+        record(ptr as usize, size);
+    }
+}
+
+/// Forgets a freed block.
+#[inline]
+pub(crate) fn freed(ptr: *mut u8) {
+    BLOCKS.shard(ptr as usize).lock().remove(ptr as usize);
+}
+
+/// Moves a block that was resized in place or remapped.
+pub(crate) fn resized(ptr: *mut u8, new_ptr: *mut u8, new_size: usize) {
+    let Some(mut block) = BLOCKS.shard(ptr as usize).lock().remove(ptr as usize) else {
+        return;
+    };
+    block.size = new_size;
+    BLOCKS
+        .shard(new_ptr as usize)
+        .lock()
+        .insert(new_ptr as usize, block);
+}
+
+fn record(addr: usize, size: usize) {
+    let options = env::options();
+    if options.leak_report && !AT_EXIT.swap(true, Ordering::Relaxed) {
+        // Registering may allocate, which is recorded like any other allocation
+        unsafe { libc::atexit(report_at_exit) };
+    }
+    let stack = if options.leak_callsite == 0 {
+        NO_STACK
+    } else {
+        BUSY.set(true);
+        let mut frames = [0; MAX_FRAMES];
+        let depth = backtrace::capture(&mut frames[..options.leak_callsite.min(MAX_FRAMES)]);
+        let stack = STACKS.lock().intern(&frames[..depth]);
+        BUSY.set(false);
+        stack.map_or(NO_STACK, |stack| stack as u32)
+    };
+    let block = Block {
+        size,
+        sequence: SEQUENCE.fetch_add(1, Ordering::Relaxed),
+        stack,
+        thread: thread(),
+    };
+    // Blocks are not recorded if the table can't grow
+    BLOCKS.shard(addr).lock().insert(addr, block);
+}
+
+extern "C" fn report_at_exit() {
+    report_leaks();
+}
+
+/// Prints every allocation that was not freed yet to stderr and returns them.
+pub fn report_leaks() -> LeakReport {
+    let report = collect(|_| true);
+    if !report.is_empty() {
+        eprint!("{report}");
+    }
+    report
+}
+
+/// Returns a checkpoint to find the allocations made after it that were not freed.
+pub fn checkpoint() -> Checkpoint {
+    Checkpoint {
+        sequence: SEQUENCE.load(Ordering::Relaxed),
+        thread: thread(),
+    }
+}
+
+/// A point in the life of the program, see [`checkpoint`].
+#[derive(Copy, Clone, Debug)]
+pub struct Checkpoint {
+    sequence: u64,
+    thread: u32,
+}
+
+impl Checkpoint {
+    /// Returns the allocations any thread made since the checkpoint that were not freed yet.
+    pub fn leaks(&self) -> LeakReport {
+        collect(|block| block.sequence >= self.sequence)
+    }
+
+    /// Returns the allocations the thread that took the checkpoint made since then that were
+    /// not freed yet. Unlike [`Checkpoint::leaks`] this ignores what other threads do meanwhile,
+    /// e.g. other tests.
+    pub fn thread_leaks(&self) -> LeakReport {
+        collect(|block| block.sequence >= self.sequence && block.thread == self.thread)
+    }
+}
+
+fn collect(filter: impl Fn(&Block) -> bool) -> LeakReport {
+    let busy = BUSY.replace(true);
+    // A free takes the lock of its shard, so nothing may be allocated or freed while one is held.
+    // The blocks are copied out into room that was reserved before.
+    let mut blocks = Vec::new();
+    for shard in BLOCKS.iter() {
+        loop {
+            let len = shard.lock().len();
+            blocks.reserve(len);
+            let table = shard.lock();
+            // Other threads may have recorded more blocks meanwhile
+            if table.len() <= blocks.capacity() - blocks.len() {
+                table.for_each(|_, block| {
+                    if filter(&block) {
+                        blocks.push(block);
+                    }
+                });
+                break;
+            }
+        }
+    }
+    let mut counts: HashMap<(usize, u32), usize> = HashMap::new();
+    for block in blocks {
+        *counts.entry((block.size, block.stack)).or_default() += 1;
+    }
+    let mut groups: Vec<LeakGroup> = {
+        let stacks = STACKS.lock();
+        counts
+            .into_iter()
+            .map(|((size, stack), count)| LeakGroup {
+                size,
+                count,
+                frames: stacks
+                    .get(stack as usize)
+                    .map_or(Vec::new(), |stack| stack.frames().to_vec()),
+            })
+            .collect()
+    };
+    groups.sort_by(|a, b| {
+        (b.size * b.count)
+            .cmp(&(a.size * a.count))
+            .then(b.size.cmp(&a.size))
+    });
+    BUSY.set(busy);
+    LeakReport { groups }
+}
+
+/// Allocations that were not freed, grouped by size and call site.
+#[derive(Clone, Debug, Default)]
+pub struct LeakReport {
+    /// The groups with the most bytes come first.
+    pub groups: Vec<LeakGroup>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LeakGroup {
+    /// The requested size of every allocation in the group.
+    pub size: usize,
+    pub count: usize,
+    /// The return addresses of the allocating stack, innermost first. Empty unless call sites
+    /// are recorded.
+    pub frames: Vec<usize>,
+}
+
+impl LeakReport {
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    /// The number of allocations that were not freed.
+    pub fn count(&self) -> usize {
+        self.groups.iter().map(|group| group.count).sum()
+    }
+
+    /// The bytes in allocations that were not freed.
+    pub fn bytes(&self) -> usize {
+        self.groups
+            .iter()
+            .map(|group| group.size * group.count)
+            .sum()
+    }
+}
+
+impl fmt::Display for LeakReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "benemalloc: {} allocations with {} bytes were not freed",
+            self.count(),
+            self.bytes()
+        )?;
+        for group in &self.groups {
+            writeln!(f, "  {} x {} bytes", group.count, group.size)?;
+            for &frame in &group.frames {
+                write!(f, "    at {frame:#x}")?;
+                write_location(f, frame)?;
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Names the object a return address is in and the offset into it, which addr2line resolves
+#[cfg(unix)]
+fn write_location(f: &mut fmt::Formatter<'_>, frame: usize) -> fmt::Result {
+    let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+    if unsafe { libc::dladdr(frame as *const libc::c_void, &mut info) } == 0
+        || info.dli_fname.is_null()
+    {
+        return Ok(());
+    }
+    let file = unsafe { std::ffi::CStr::from_ptr(info.dli_fname) }.to_string_lossy();
+    write!(f, " ({file}+{:#x})", frame - info.dli_fbase as usize)
+}
+
+#[cfg(not(unix))]
+fn write_location(_f: &mut fmt::Formatter<'_>, _frame: usize) -> fmt::Result {
+    Ok(())
+}
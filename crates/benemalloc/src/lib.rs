@@ -1,236 +1,987 @@
 //! This is a simple memory allocator written in Rust.
-// TODO: Use mremap to grow memory allocations instead of reallocating them
 // TODO: Make this work on stable, add stable to ci
+#![cfg_attr(feature = "nightly", feature(allocator_api))]
 
+#[cfg(feature = "nightly")]
+mod allocator_api;
+mod arena;
+#[cfg(any(
+    feature = "track_allocations",
+    feature = "heap_profile",
+    feature = "leak_check"
+))]
+mod backtrace;
+mod builder;
+mod decay;
+mod env;
+#[cfg(feature = "hardened")]
+mod hardened;
+mod heap;
+#[cfg(feature = "leak_check")]
+pub mod leaks;
+#[cfg(feature = "heap_profile")]
+pub mod profile;
+mod size_class;
+mod slab;
+mod spin;
+mod stats;
+mod table;
+pub mod trace;
 #[cfg(feature = "track_allocations")]
 mod tracker;
 
-use std::cell::UnsafeCell;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::{alloc::GlobalAlloc, num::NonZeroUsize, os::raw::c_void};
+use std::cell::{Cell, UnsafeCell};
+use std::hint::spin_loop;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::{alloc::GlobalAlloc, os::raw::c_void};
 
-// Global flag to disable thread-local caching when process is in unstable state
-static GLOBAL_CACHE_ENABLED: AtomicBool = AtomicBool::new(true);
+// How often an allocation or free could not use the heap of its thread
+static FALLBACKS: AtomicUsize = AtomicUsize::new(0);
 
-#[cfg(feature = "debug")]
 use std::alloc::Layout;
 
-use allocations::{allocate, deallocate};
+pub use builder::{Builder, FreeFill};
+pub use decay::{collect, trim};
+pub use heap::Heap;
+#[cfg(feature = "leak_check")]
+pub use leaks::report_leaks;
+pub use stats::{ClassStats, SIZE_CLASSES, Stats, stats};
+
+#[cfg(not(feature = "hardened"))]
+use allocations::{allocate, allocate_aligned, deallocate_aligned};
+use size_class::{NUM_CLASSES, block_size, class_for, class_size};
+use slab::{NUM_SMALL_CLASSES, SMALL_MAX, Slabs};
+use spin::SpinLock;
+use stats::ThreadStats;
+
+// The smallest page size of the supported platforms. Blocks that get a mapping of their own are
+// at least aligned to this.
+const PAGE_SIZE: usize = 4096;
+
+// Randomized reuse picks from this many of the most recently freed blocks of a class
+const REUSE_WINDOW: usize = 16;
+// What freed blocks are filled with by FreeFill::Poison
+const POISON: u8 = 0xde;
+
+// Slabs keep every slot aligned to its size class, but the bins of large classes only hold
+// page-aligned mappings. Large blocks with a larger alignment get an aligned mapping of their own.
+const fn is_over_aligned(layout: Layout) -> bool {
+    layout.align() > PAGE_SIZE && block_size(layout) > SMALL_MAX
+}
+
+/// The layout of the block that serves `layout`. With the `hardened` feature small objects get
+/// room for a canary after them.
+#[inline]
+const fn padded(layout: Layout) -> Layout {
+    #[cfg(feature = "hardened")]
+    let layout = hardened::padded(layout);
+    layout
+}
+
+/// Gives a block with a mapping of its own back to the OS. Alignments up to the page size are
+/// all mapped the same way.
+///
+/// # Safety
+/// ptr must be a block of `size` bytes mapped by [`BeneAlloc::map`] with `align` that is not used
+/// anymore.
+unsafe fn release(ptr: *mut u8, size: usize, align: usize) {
+    #[cfg(feature = "hardened")]
+    unsafe {
+        hardened::unmap_guarded(ptr, size, align)
+    };
+    #[cfg(not(feature = "hardened"))]
+    unsafe {
+        deallocate_aligned(ptr as *mut c_void, size, align)
+    };
+    stats::unmapped(size);
+}
 
 #[cfg(not(target_os = "macos"))]
 thread_local! {
-    static CURRENT_THREAD_ALLOCATOR: UnsafeCell<InternalState<512>> = const {UnsafeCell::new(InternalState::new()) };
+    static CURRENT_THREAD_ALLOCATOR: UnsafeCell<[InternalState; 2]> = const {UnsafeCell::new([InternalState::new(false), InternalState::new(true)]) };
+    static THREAD_STATE: Cell<ThreadState> = const { Cell::new(ThreadState::New) };
+    // Zero until it is seeded
+    static REUSE_RNG: Cell<u64> = const { Cell::new(0) };
 
     #[cfg(feature = "track_allocations")]
     static THREAD_TRACKER: UnsafeCell<tracker::Tracker> = const {UnsafeCell::new(tracker::Tracker::new()) };
+    // Starts out New like the heap, the tracker registers a destructor of its own
+    #[cfg(feature = "track_allocations")]
+    static TRACKER_STATE: Cell<ThreadState> = const { Cell::new(ThreadState::New) };
 }
 
 #[cfg(target_os = "macos")]
 thread_local! {
-    static CURRENT_THREAD_ALLOCATOR: UnsafeCell<InternalState<512>> = const {UnsafeCell::new(InternalState::new()) };
+    static CURRENT_THREAD_ALLOCATOR: UnsafeCell<[InternalState; 2]> = const {UnsafeCell::new([InternalState::new(false), InternalState::new(true)]) };
+    static THREAD_STATE: Cell<ThreadState> = const { Cell::new(ThreadState::New) };
+    // Zero until it is seeded
+    static REUSE_RNG: Cell<u64> = const { Cell::new(0) };
 
     #[cfg(feature = "track_allocations")]
     static THREAD_TRACKER: UnsafeCell<tracker::Tracker> = const {UnsafeCell::new(tracker::Tracker::new()) };
+    // Starts out New like the heap, the tracker registers a destructor of its own
+    #[cfg(feature = "track_allocations")]
+    static TRACKER_STATE: Cell<ThreadState> = const { Cell::new(ThreadState::New) };
 }
 
-// Defines the bounds of a memory block. Rust says ptr is not Thread-safe, however since we are the allocator it should be.
-#[derive(Debug, Copy, Clone)]
-struct Block {
-    size: usize,
-    ptr: *mut u8,
+// Whether the heap of a thread may be used. This lives apart from the heap and has no destructor,
+// so it can still be read while the heap is being torn down.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum ThreadState {
+    // The heap of the thread was never used
+    New,
+    // The heap is registering its destructor, which may allocate
+    Starting,
+    Active,
+    // The thread is exiting and its heap is being handed over to the others, everything it
+    // allocates or frees from now on goes through the global heap
+    TearingDown,
+}
+
+// A freed block waiting in a bin or slab. The link to the next free block of the same class is
+// stored in the block itself, so caching a block costs no memory besides the block.
+// Blocks are at least 8 byte aligned, which leaves the lowest bit of the link free to mark blocks
+// that are known to be zero apart from the link itself.
+struct FreeBlock {
+    link: usize,
 }
-unsafe impl Send for Block {}
-unsafe impl Sync for Block {}
 
-struct InternalState<const SIZE: usize> {
+impl FreeBlock {
+    const ZEROED: usize = 1;
+
+    /// # Safety
+    /// block must be valid for writes and at least 8 byte aligned.
+    unsafe fn init(block: *mut FreeBlock, next: *mut FreeBlock, zeroed: bool) {
+        let link = next as usize | if zeroed { Self::ZEROED } else { 0 };
+        unsafe { block.write(FreeBlock { link }) };
+    }
+
+    fn next(&self) -> *mut FreeBlock {
+        (self.link & !Self::ZEROED) as *mut FreeBlock
+    }
+
+    fn set_next(&mut self, next: *mut FreeBlock) {
+        self.link = next as usize | (self.link & Self::ZEROED);
+    }
+
+    fn zeroed(&self) -> Zeroed {
+        if self.link & Self::ZEROED != 0 {
+            Zeroed::BesidesLink
+        } else {
+            Zeroed::No
+        }
+    }
+}
+
+// How much of a newly handed out block is already known to be zero, so alloc_zeroed only clears
+// what is necessary.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Zeroed {
+    // Fresh from the OS
+    All,
+    // Never used, but it was linked into a free list
+    BesidesLink,
+    No,
+}
+
+impl Zeroed {
+    /// Clears the first `size` bytes of a block that was handed out in this state.
+    ///
+    /// # Safety
+    /// ptr must be a block of at least `size` bytes.
+    unsafe fn clear(self, ptr: *mut u8, size: usize) {
+        match self {
+            Zeroed::All => {}
+            // Every block is large enough to hold the link
+            Zeroed::BesidesLink => unsafe { (ptr as *mut FreeBlock).write_bytes(0, 1) },
+            Zeroed::No => unsafe { ptr.write_bytes(0, size) },
+        }
+    }
+}
+
+// An intrusive free list of blocks that all have the size of one size class.
+#[derive(Copy, Clone)]
+struct Bin {
+    head: *mut FreeBlock,
+    len: usize,
+    // The fewest blocks the bin held since the last decay. Blocks are taken from the head, so
+    // this many blocks at the tail were not reused since.
+    low: usize,
+}
+
+impl Bin {
+    const fn new() -> Self {
+        Self {
+            head: std::ptr::null_mut(),
+            len: 0,
+            low: 0,
+        }
+    }
+}
+
+// One bin per size class.
+struct Bins {
+    // The number of blocks cached over all bins
     size: usize,
-    // TODO: The elements should not be Option<Block> but a union since we track the size manually
-    free_array: [Option<Block>; SIZE],
+    bins: [Bin; NUM_CLASSES],
 }
 
-impl<const SIZE: usize> InternalState<SIZE> {
+// The blocks are only touched by whoever holds the bins
+unsafe impl Send for Bins {}
+
+impl Bins {
     const fn new() -> Self {
         Self {
             size: 0,
-            free_array: [None; SIZE],
+            bins: [Bin::new(); NUM_CLASSES],
         }
     }
-    fn insert(&mut self, block: Block) {
-        self.free_array[self.size] = Some(block);
+
+    /// Caches a block of the given class. Returns false if `capacity` blocks are cached already.
+    ///
+    /// # Safety
+    /// ptr must point to an unused block of exactly `class_size(class)` bytes.
+    unsafe fn insert(&mut self, class: usize, ptr: *mut u8, capacity: usize) -> bool {
+        if self.size >= capacity {
+            return false;
+        }
+        let block = ptr as *mut FreeBlock;
+        let bin = &mut self.bins[class];
+        unsafe { FreeBlock::init(block, bin.head, false) };
+        bin.head = block;
+        bin.len += 1;
         self.size += 1;
+        true
+    }
+
+    /// Takes a cached block of the given class, if there is one.
+    fn take(&mut self, class: usize) -> Option<(*mut u8, Zeroed)> {
+        let bin = &mut self.bins[class];
+        let block = bin.head;
+        if block.is_null() {
+            return None;
+        }
+        // Every block in a bin was written by insert or attach, so the link is initialized
+        let zeroed = unsafe { (*block).zeroed() };
+        bin.head = unsafe { (*block).next() };
+        bin.len -= 1;
+        bin.low = bin.low.min(bin.len);
+        self.size -= 1;
+        Some((block as *mut u8, zeroed))
     }
 
-    fn get_fitting_index(&self, size: usize, align: NonZeroUsize) -> Option<usize> {
-        let freeblocks_size = self.size;
-        for i in 0..freeblocks_size {
-            if let Some(block) = self.free_array[i] {
-                // Since align must be a power of two and cannot be zero we can safely do new_unchecked
-                // TODO: This is somehow slower according to mca as align is first converted to NonZero
-                if block.size >= size && (block.ptr as usize % align) == 0 {
-                    return Some(i);
+    /// Takes the `n`th block of the bin of the given class, counting from the most recently
+    /// freed one. `n` must be less than the number of blocks in the bin.
+    fn take_nth(&mut self, class: usize, n: usize) -> (*mut u8, Zeroed) {
+        let bin = &mut self.bins[class];
+        debug_assert!(n < bin.len);
+        let block = unsafe {
+            if n == 0 {
+                let block = bin.head;
+                bin.head = (*block).next();
+                block
+            } else {
+                let mut prev = bin.head;
+                for _ in 1..n {
+                    prev = (*prev).next();
                 }
+                let block = (*prev).next();
+                (*prev).set_next((*block).next());
+                block
+            }
+        };
+        bin.len -= 1;
+        bin.low = bin.low.min(bin.len);
+        self.size -= 1;
+        (block as *mut u8, unsafe { (*block).zeroed() })
+    }
+
+    /// Prepends a list of `count` blocks of the given class to its bin.
+    ///
+    /// # Safety
+    /// The list must be null-terminated and consist of `count` unused blocks of the class.
+    unsafe fn attach(&mut self, class: usize, head: *mut FreeBlock, count: usize) {
+        if count == 0 {
+            return;
+        }
+        let bin = &mut self.bins[class];
+        let mut tail = head;
+        unsafe {
+            while !(*tail).next().is_null() {
+                tail = (*tail).next();
+            }
+            (*tail).set_next(bin.head);
+        }
+        bin.head = head;
+        bin.len += count;
+        self.size += count;
+    }
+
+    /// Unlinks up to `count` blocks from the bin of the given class and returns them as a
+    /// null-terminated list.
+    fn detach(&mut self, class: usize, count: usize) -> *mut FreeBlock {
+        let bin = &mut self.bins[class];
+        let count = count.min(bin.len);
+        if count == 0 {
+            return std::ptr::null_mut();
+        }
+        let head = bin.head;
+        let mut tail = head;
+        unsafe {
+            for _ in 1..count {
+                tail = (*tail).next();
             }
+            bin.head = (*tail).next();
+            (*tail).set_next(std::ptr::null_mut());
         }
-        None
+        bin.len -= count;
+        bin.low = bin.low.min(bin.len);
+        self.size -= count;
+        head
+    }
+
+    /// Unlinks up to `count` of the blocks that were cached the longest from the bin of the given
+    /// class and returns them as a null-terminated list.
+    fn detach_oldest(&mut self, class: usize, count: usize) -> *mut FreeBlock {
+        let bin = &mut self.bins[class];
+        let count = count.min(bin.len);
+        if count == 0 {
+            return std::ptr::null_mut();
+        }
+        let keep = bin.len - count;
+        let head = if keep == 0 {
+            std::mem::replace(&mut bin.head, std::ptr::null_mut())
+        } else {
+            let mut last = bin.head;
+            unsafe {
+                for _ in 1..keep {
+                    last = (*last).next();
+                }
+                let head = (*last).next();
+                (*last).set_next(std::ptr::null_mut());
+                head
+            }
+        };
+        bin.len -= count;
+        bin.low = bin.low.min(bin.len);
+        self.size -= count;
+        head
+    }
+}
+
+// Bins of small classes are refilled from and flushed to the slabs of the thread in batches, the
+// bins of large classes hold whole mappings.
+struct InternalState {
+    bins: Bins,
+    slabs: Slabs,
+    stats: ThreadStats,
+    // Allocations and frees since the last decay
+    ticks: usize,
+}
+
+impl InternalState {
+    const fn new(never_unmap: bool) -> Self {
+        let mut slabs = Slabs::new();
+        slabs.never_unmap = never_unmap;
+        Self {
+            bins: Bins::new(),
+            slabs,
+            stats: ThreadStats::new(),
+            ticks: 0,
+        }
+    }
+
+    /// Takes a cached block of the given class, a random one of the most recently freed ones if
+    /// reuse is randomized.
+    #[inline]
+    fn take(&mut self, class: usize, randomize: bool) -> Option<(*mut u8, Zeroed)> {
+        if randomize {
+            return self.take_random(class);
+        }
+        self.bins.take(class)
+    }
+
+    #[cold]
+    fn take_random(&mut self, class: usize) -> Option<(*mut u8, Zeroed)> {
+        let len = self.bins.bins[class].len;
+        if len < 2 {
+            return self.bins.take(class);
+        }
+        let n = random() as usize % len.min(REUSE_WINDOW);
+        Some(self.bins.take_nth(class, n))
+    }
+
+    /// Registers the stats of the heap once it is used.
+    #[inline]
+    fn register(&self) {
+        // The heap lives in a thread-local, so it doesn't move until the thread exits
+        self.stats.register();
+    }
+
+    /// The large blocks of exited threads this heap may take, and hands its own over to.
+    fn orphans(&self) -> &'static SpinLock<Bins> {
+        &ORPHANS[self.slabs.never_unmap as usize]
+    }
+}
+
+/// Runs `f` on the heap of the current thread that serves allocators with `never_unmap`.
+///
+/// All allocators share the heaps of a thread, but those that never unmap memory get one of
+/// their own. Their blocks are bumped from the arena, which must neither be unmapped by nor
+/// handed out to an allocator that unmaps what it frees. The other options only decide what a
+/// single allocation or free does, so they may differ between the allocators of a heap.
+#[inline]
+fn with_thread_heap<R>(
+    never_unmap: bool,
+    f: impl FnOnce(&mut InternalState) -> R,
+) -> Result<R, std::thread::AccessError> {
+    CURRENT_THREAD_ALLOCATOR
+        .try_with(|heaps| f(unsafe { &mut (*heaps.get())[never_unmap as usize] }))
+}
+
+// xorshift64 for randomized reuse
+fn random() -> u64 {
+    let mut x = REUSE_RNG.get();
+    if x == 0 {
+        // The address of the state differs between threads and, through ASLR, between runs
+        x = &REUSE_RNG as *const _ as u64
+            ^ std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |time| time.as_nanos() as u64)
+            | 1;
+    }
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    REUSE_RNG.set(x);
+    x
+}
+
+// Runs when the thread exits. Nothing the thread cached is lost: the small slots go back to the
+// segments, which are then abandoned for other threads to adopt, and the large mappings are
+// handed to the orphan pool.
+impl Drop for InternalState {
+    fn drop(&mut self) {
+        let _ = THREAD_STATE.try_with(|state| state.set(ThreadState::TearingDown));
+        for class in 0..NUM_SMALL_CLASSES {
+            let list = self.bins.detach(class, usize::MAX);
+            // Only slots of our own segments are ever cached
+            unsafe { self.slabs.give_back(class, list) };
+        }
+        let mut orphans = self.orphans().lock();
+        for class in NUM_SMALL_CLASSES..NUM_CLASSES {
+            while let Some((block, _)) = self.bins.take(class) {
+                let capacity = if self.slabs.never_unmap {
+                    usize::MAX
+                } else {
+                    ORPHAN_CAPACITY
+                };
+                if !unsafe { orphans.insert(class, block, capacity) } {
+                    unsafe { release(block, class_size(class), PAGE_SIZE) };
+                }
+            }
+        }
+        drop(orphans);
+        self.slabs.abandon();
+        self.stats.unregister();
     }
 }
 
+/// Large blocks cached by threads that exited, for the remaining threads to take. Blocks that
+/// are never unmapped are kept apart, indexed like the heaps of a thread.
+static ORPHANS: [SpinLock<Bins>; 2] = [const { SpinLock::new(Bins::new()) }; 2];
+const ORPHAN_CAPACITY: usize = 512;
+
 pub struct BeneAlloc {
-    #[cfg(feature = "debug")]
-    pub allocations: [Option<Layout>; 4096],
+    // Gets the overrides from the environment applied on first use, by the thread that flips
+    // `resolved` to RESOLVING, before it is set to RESOLVED
+    config: UnsafeCell<Builder>,
+    resolved: AtomicU8,
 }
 
+const UNRESOLVED: u8 = 0;
+const RESOLVING: u8 = 1;
+const RESOLVED: u8 = 2;
+
 unsafe impl Sync for BeneAlloc {}
 unsafe impl Send for BeneAlloc {}
 
+impl Default for BeneAlloc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl BeneAlloc {
+    /// Returns how many bytes an allocation of `layout` really has. A request is served by a
+    /// block of its size class, so the memory past `layout.size()` up to this size may be used
+    /// as well. With the `hardened` feature the rest of a small block holds a canary, so small
+    /// allocations have just their size.
+    pub const fn usable_size(layout: Layout) -> usize {
+        #[cfg(feature = "hardened")]
+        if hardened::has_canary(layout) {
+            return layout.size();
+        }
+        block_size(layout)
+    }
+
+    /// Creates an allocator with the default configuration, see [`Builder`] for the defaults.
     pub const fn new() -> Self {
+        Self::builder().build()
+    }
+
+    pub const fn builder() -> Builder {
+        Builder::new()
+    }
+
+    pub(crate) const fn with_config(config: Builder) -> Self {
         Self {
-            #[cfg(feature = "debug")]
-            allocations: [None; 4096],
+            config: UnsafeCell::new(config),
+            resolved: AtomicU8::new(UNRESOLVED),
         }
     }
 
+    /// Returns how often an allocation or free could not use the heap of its thread and went
+    /// through the global heap instead, because the thread was exiting and its heap was gone.
+    pub fn fallback_count() -> usize {
+        FALLBACKS.load(Ordering::Relaxed)
+    }
+
     #[cfg(feature = "track_allocations")]
     pub fn print(&self) {
-        let _ = THREAD_TRACKER.try_with(|tracker| unsafe {
-            tracker.get().as_ref().unwrap().print();
-        });
+        let stats = stats();
+        println!("Allocations: {}, Frees: {}", stats.allocations, stats.frees);
+        println!(
+            "Allocated Size: {} (peak {})",
+            stats.current_bytes, stats.peak_bytes
+        );
+    }
+
+    /// Writes the events the current thread buffered to the trace. Other threads write theirs
+    /// when their buffer is full or when they exit.
+    #[cfg(feature = "track_allocations")]
+    pub fn flush_trace(&self) {
+        let _ = THREAD_TRACKER.try_with(|tracker| unsafe { (*tracker.get()).flush() });
     }
 }
 
-unsafe impl GlobalAlloc for BeneAlloc {
-    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
-        // During panic unwinding, bypass thread-local cache to avoid issues
-        if std::thread::panicking() {
-            return allocate(layout.size()) as *mut u8;
-        }
-
-        // Check global cache flag - if disabled, use system allocator
-        if !GLOBAL_CACHE_ENABLED.load(Ordering::Relaxed) {
-            return allocate(layout.size()) as *mut u8;
-        }
-
-        // Try to get a block from the cache
-        let result = CURRENT_THREAD_ALLOCATOR.try_with(|state| unsafe {
-            let state = &mut *state.get();
-            let freeblocks_size = state.size;
-            let _size = layout.size();
-            // The trait guarantees us that align is not zero, so this is safe.
-            let align = NonZeroUsize::new_unchecked(layout.align());
-
-            for i in 0..freeblocks_size {
-                if let Some(block) = state.free_array[i] {
-                    // Check if block is suitable: large enough and properly aligned
-                    if block.size >= layout.size() && (block.ptr as usize % align.get()) == 0 {
-                        let original_ptr = block.ptr;
-
-                        // Remove this block from the free list
-                        // Place the last block at the current position
-                        state.free_array[i] = state.free_array[freeblocks_size.saturating_sub(1)];
-                        state.free_array[freeblocks_size.saturating_sub(1)] = None;
-                        state.size -= 1;
-
-                        debug_assert!(
-                            original_ptr as usize % layout.align() == 0,
-                            "Alignment error. ptr: {:?}, align: {}",
-                            original_ptr,
-                            layout.align()
-                        );
-
-                        #[cfg(feature = "track_allocations")]
-                        {
-                            use crate::tracker::Action;
-                            use crate::tracker::Event;
-                            let _ = THREAD_TRACKER.try_with(|tracker| {
-                                let tracker = &mut *tracker.get();
-                                tracker.track(Event::Alloc {
-                                    addr: original_ptr as usize,
-                                    size: layout.size() as usize,
-                                    source: Action::Cache,
-                                });
-                            });
-                        }
-                        return Some(original_ptr as *mut u8);
-                    }
-                }
+impl BeneAlloc {
+    /// The configuration of the builder, with the overrides from the environment applied.
+    /// They are applied once, so this is a single load on every allocation and free.
+    #[inline]
+    fn config(&self) -> &Builder {
+        if self.resolved.load(Ordering::Acquire) != RESOLVED {
+            self.resolve();
+        }
+        unsafe { &*self.config.get() }
+    }
+
+    // Resolving doesn't allocate, so no allocation can come back here while one thread resolves.
+    // Threads racing it just wait until it is done.
+    #[cold]
+    fn resolve(&self) {
+        if self
+            .resolved
+            .compare_exchange(UNRESOLVED, RESOLVING, Ordering::Acquire, Ordering::Acquire)
+            .is_err()
+        {
+            while self.resolved.load(Ordering::Acquire) != RESOLVED {
+                spin_loop();
             }
-            None
+            return;
+        }
+        unsafe { *self.config.get() = env::configure(*self.config.get()) };
+        self.resolved.store(RESOLVED, Ordering::Release);
+    }
+
+    /// Returns whether the current thread may use its own heap. While the thread starts its heap
+    /// and while it exits, the global heap is used instead. Unwinding threads keep their heap, it
+    /// stays valid until their thread-locals are destroyed.
+    #[inline]
+    fn heap_usable() -> bool {
+        match THREAD_STATE.try_with(Cell::get) {
+            Ok(ThreadState::Active) => true,
+            _ => Self::heap_usable_slow(),
+        }
+    }
+
+    #[cold]
+    fn heap_usable_slow() -> bool {
+        THREAD_STATE
+            .try_with(|state| match state.get() {
+                ThreadState::Active => true,
+                ThreadState::New => Self::start_thread(state),
+                ThreadState::Starting | ThreadState::TearingDown => false,
+            })
+            .unwrap_or(false)
+    }
+
+    /// Counts an allocation or free that went through the global heap because the heap of the
+    /// thread is gone. The ones of a thread starting its heap are part of every thread start, so
+    /// they aren't counted.
+    #[cold]
+    fn count_fallback() {
+        if !matches!(THREAD_STATE.try_with(Cell::get), Ok(ThreadState::Starting)) {
+            FALLBACKS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Touches the heap of a new thread, which registers its destructor. That may allocate, e.g.
+    /// glibc does with `calloc` when benemalloc serves C code, and those allocations go through
+    /// the global heap instead of recursing into the registration.
+    #[cold]
+    fn start_thread(state: &Cell<ThreadState>) -> bool {
+        state.set(ThreadState::Starting);
+        let started = CURRENT_THREAD_ALLOCATOR.try_with(|_| {}).is_ok();
+        state.set(ThreadState::Active);
+        started
+    }
+
+    /// Allocates through the global heap, for when the heap of the thread is unavailable.
+    fn alloc_fallback(&self, layout: Layout) -> (*mut u8, Zeroed) {
+        Self::count_fallback();
+        let (ptr, zeroed) = self.alloc_uncached(layout);
+        #[cfg(feature = "track_allocations")]
+        track(tracker::Event::Alloc {
+            addr: ptr as usize,
+            size: layout.size(),
+            source: tracker::Action::System,
         });
+        (ptr, zeroed)
+    }
 
-        match result {
-            Ok(Some(ptr)) => ptr,
-            Ok(None) | Err(_) => {
-                // No suitable block in cache or thread-local unavailable, allocate from system
-                if result.is_err() {
-                    // Thread-local access failed, disable cache globally
-                    GLOBAL_CACHE_ENABLED.store(false, Ordering::Relaxed);
-                }
+    /// Allocates without touching the thread cache, for when it is unavailable.
+    fn alloc_uncached(&self, layout: Layout) -> (*mut u8, Zeroed) {
+        let (ptr, zeroed) = match class_for(layout) {
+            Some(class) if class < NUM_SMALL_CLASSES => slab::take_global(class),
+            _ => (self.map(block_size(layout), layout.align()), Zeroed::All),
+        };
+        if !ptr.is_null() {
+            stats::alloc_shared(class_for(layout), block_size(layout));
+        }
+        (ptr, zeroed)
+    }
+
+    /// Maps a block of its own, or bumps it from the arena if memory is never unmapped.
+    /// The block is zero either way.
+    fn map(&self, size: usize, align: usize) -> *mut u8 {
+        // Guard pages need a mapping of their own, even if it is never unmapped
+        #[cfg(feature = "hardened")]
+        let (ptr, bumped) = (hardened::map_guarded(size, align), false);
+        #[cfg(not(feature = "hardened"))]
+        let (ptr, bumped) = if self.config().never_unmap {
+            (arena::bump(size, align), true)
+        } else if align > PAGE_SIZE {
+            (allocate_aligned(size, align) as *mut u8, false)
+        } else {
+            (allocate(size) as *mut u8, false)
+        };
+        // The arena counts its regions itself
+        if !ptr.is_null() && !bumped {
+            stats::mapped(size);
+        }
+        if ptr.is_null() && env::options().verbose {
+            env::log(format_args!("failed to map {size} bytes"));
+        }
+        ptr
+    }
 
-                let ret = allocate(layout.size());
-                debug_assert!(ret as usize % layout.align() == 0);
+    /// Allocates a block and reports how much of it is known to be zero.
+    fn alloc_block(&self, layout: Layout) -> (*mut u8, Zeroed) {
+        if is_over_aligned(layout) {
+            let ptr = self.map(block_size(layout), layout.align());
+            if !ptr.is_null() {
+                stats::alloc_shared(class_for(layout), block_size(layout));
+            }
+            #[cfg(feature = "track_allocations")]
+            track(tracker::Event::Alloc {
+                addr: ptr as usize,
+                size: layout.size(),
+                source: tracker::Action::System,
+            });
+            return (ptr, Zeroed::All);
+        }
+
+        let Some(class) = class_for(layout) else {
+            // Too large to be binned, so it gets its own mapping
+            let ptr = self.map(layout.size(), layout.align());
+            if !ptr.is_null() {
+                stats::alloc_shared(None, layout.size());
+            }
+            #[cfg(feature = "track_allocations")]
+            track(tracker::Event::Alloc {
+                addr: ptr as usize,
+                size: layout.size(),
+                source: tracker::Action::System,
+            });
+            return (ptr, Zeroed::All);
+        };
+
+        if !Self::heap_usable() {
+            return self.alloc_fallback(layout);
+        }
+
+        // Try to get a block from the bin of this size class, small classes refill their bin
+        // from the slab
+        let config = self.config();
+        let result = with_thread_heap(config.never_unmap, |state| unsafe {
+            state.register();
+            state.tick(config);
+            if let Some((block, zeroed)) = state.take(class, config.randomize_reuse) {
+                state.stats.alloc(Some(class), class_size(class), true);
+                #[cfg(feature = "track_allocations")]
+                track(tracker::Event::Alloc {
+                    addr: block as usize,
+                    size: layout.size(),
+                    source: tracker::Action::Cache,
+                });
+                return Some((block, zeroed));
+            }
+            if class >= NUM_SMALL_CLASSES {
+                // Maybe a thread that exited left a block of this class behind
+                let (block, zeroed) = state.orphans().lock().take(class)?;
+                state.stats.alloc(Some(class), class_size(class), false);
                 #[cfg(feature = "track_allocations")]
-                {
-                    use crate::tracker::Action;
-                    use crate::tracker::Event;
-                    let _ = THREAD_TRACKER.try_with(|tracker| {
-                        let tracker = &mut *tracker.get();
-                        tracker.track(Event::Alloc {
-                            addr: ret as usize,
-                            size: layout.size() as usize,
-                            source: Action::System,
-                        });
+                track(tracker::Event::Alloc {
+                    addr: block as usize,
+                    size: layout.size(),
+                    source: tracker::Action::Cache,
+                });
+                return Some((block, zeroed));
+            }
+            // The block handed out isn't cached, the rest of the batch has to fit the cache
+            let room = config.thread_cache_capacity.saturating_sub(state.bins.size);
+            let batch = slab::batch_size(class).min(room + 1);
+            let (head, count) = state.slabs.take(class, batch);
+            state.bins.attach(class, head, count);
+            let (block, zeroed) = state.take(class, config.randomize_reuse)?;
+            state.stats.alloc(Some(class), class_size(class), false);
+            #[cfg(feature = "track_allocations")]
+            track(tracker::Event::Alloc {
+                addr: block as usize,
+                size: layout.size(),
+                source: tracker::Action::Slab,
+            });
+            Some((block, zeroed))
+        });
+
+        match result {
+            Ok(Some((ptr, zeroed))) => {
+                debug_assert!(
+                    (ptr as usize).is_multiple_of(layout.align()),
+                    "Alignment error. ptr: {:?}, align: {}",
+                    ptr,
+                    layout.align()
+                );
+                (ptr, zeroed)
+            }
+            Ok(None) | Err(_) => {
+                // No suitable block in cache, or the thread-local is already gone because the
+                // thread is exiting
+                let (ret, zeroed) = if result.is_err() {
+                    self.alloc_fallback(layout)
+                } else {
+                    let (ret, zeroed) = self.alloc_uncached(layout);
+                    #[cfg(feature = "track_allocations")]
+                    track(tracker::Event::Alloc {
+                        addr: ret as usize,
+                        size: layout.size(),
+                        source: tracker::Action::System,
                     });
-                }
-                ret as *mut u8
+                    (ret, zeroed)
+                };
+                debug_assert!((ret as usize).is_multiple_of(layout.align()));
+                (ret, zeroed)
             }
         }
     }
-    /// The caller must ensure the ptr and layout are valid, so we do not have to keep track of
-    /// how much memory was allocated for a given pointer. This helps us, because we do not have to
-    /// modify the allocated list in other threads, which would require some kind of synchronization.
-    /// Instead, we can add it to the local `free` list or deallocate it directly.
+
+    /// Grows or shrinks a block that has a mapping of its own with mremap, so the kernel moves
+    /// the pages instead of us copying them. Returns null if either size is carved from a slab or
+    /// the kernel could not remap the block.
     ///
     /// # Safety
-    /// The caller must ensure ptr and layout are valid. Additionally, the ptr may not be used after this function is called as any use would be UAF
-    /// The caller must ensure the ptr was allocated by this allocator. Other allocators used(say for C libraries) do need to be deallocated by
-    /// that allocator as to not corrupt this allocator's state
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
-        // During panic unwinding, bypass thread-local cache to avoid issues
-        if std::thread::panicking() {
-            deallocate(ptr as *mut c_void, layout.size());
+    /// ptr must have been allocated by this allocator with the given layout.
+    #[cfg(target_os = "linux")]
+    unsafe fn remap(ptr: *mut u8, layout: Layout, new_layout: Layout) -> *mut u8 {
+        // mremap only keeps page alignment, and guard pages would stay behind
+        if layout.align() > PAGE_SIZE || cfg!(feature = "hardened") {
+            return std::ptr::null_mut();
+        }
+        let old_size = block_size(layout);
+        let new_size = block_size(new_layout);
+        if old_size <= SMALL_MAX || new_size <= SMALL_MAX {
+            return std::ptr::null_mut();
+        }
+        let new_ptr =
+            unsafe { allocations::realloc(ptr as *mut c_void, old_size, new_size) as *mut u8 };
+        if !new_ptr.is_null() {
+            stats::resize_shared(class_for(layout), old_size, class_for(new_layout), new_size);
+            stats::remapped(old_size, new_size);
+        }
+        new_ptr
+    }
+
+    /// # Safety
+    /// ptr must have been allocated by this allocator with the given layout.
+    unsafe fn dealloc_uncached(&self, ptr: *mut u8, layout: Layout) {
+        stats::free_shared(class_for(layout), block_size(layout));
+        match class_for(layout) {
+            Some(class) if class < NUM_SMALL_CLASSES => unsafe { slab::free_global(class, ptr) },
+            _ => unsafe { self.unmap(ptr, block_size(layout), layout.align()) },
+        }
+    }
+
+    /// Gives a block with a mapping of its own back to the OS, unless memory is never unmapped.
+    ///
+    /// # Safety
+    /// ptr must be a block of `size` bytes mapped with `align` that is not used anymore.
+    unsafe fn unmap(&self, ptr: *mut u8, size: usize, align: usize) {
+        if !self.config().never_unmap {
+            unsafe { release(ptr, size, align) };
+        }
+    }
+
+    /// Overwrites a freed block as configured. Blocks that are unmapped right away take their
+    /// contents with them.
+    ///
+    /// # Safety
+    /// ptr must be a block of `layout` that is being freed.
+    #[cold]
+    unsafe fn fill_freed(config: &Builder, ptr: *mut u8, layout: Layout) {
+        let byte = match config.free_fill {
+            FreeFill::Keep => return,
+            FreeFill::Zero => 0,
+            FreeFill::Poison => POISON,
+        };
+        if (is_over_aligned(layout) || class_for(layout).is_none()) && !config.never_unmap {
+            return;
+        }
+        unsafe { ptr.write_bytes(byte, block_size(layout)) };
+    }
+
+    /// Caches a small block in the bin of its class, without reading the block or its segment.
+    /// Returns false unless the block is in the segment the thread last took slots of the class
+    /// from, or if the cache of the thread is full or unavailable.
+    ///
+    /// # Safety
+    /// ptr must be a block of `class` that is being freed.
+    #[inline]
+    unsafe fn free_cached(&self, ptr: *mut u8, class: usize) -> bool {
+        let config = self.config();
+        if config.free_fill != FreeFill::Keep || !Self::heap_usable() {
+            return false;
+        }
+        with_thread_heap(config.never_unmap, |state| unsafe {
+            // Any other segment may belong to another thread, the slow path looks that up
+            if !state.slabs.recently_taken(class, ptr)
+                || !state.bins.insert(class, ptr, config.thread_cache_capacity)
+            {
+                return false;
+            }
+            state.register();
+            state.tick(config);
+            state.stats.free(Some(class), class_size(class));
+            true
+        })
+        .unwrap_or(false)
+    }
+
+    /// Frees everything [`BeneAlloc::free_cached`] doesn't: large blocks, blocks that are filled,
+    /// blocks of other segments and blocks that don't fit into the cache.
+    ///
+    /// # Safety
+    /// ptr must have been allocated by this allocator with `layout`, including its padding.
+    #[inline(never)]
+    unsafe fn dealloc_slow(&self, ptr: *mut u8, layout: Layout) {
+        let config = self.config();
+        if config.free_fill != FreeFill::Keep {
+            unsafe { Self::fill_freed(config, ptr, layout) };
+        }
+
+        if is_over_aligned(layout) {
+            #[cfg(feature = "track_allocations")]
+            track(tracker::Event::Free {
+                addr: ptr as usize,
+                size: layout.size(),
+                action: tracker::Action::System,
+            });
+            stats::free_shared(class_for(layout), block_size(layout));
+            unsafe { self.unmap(ptr, block_size(layout), layout.align()) };
             return;
         }
 
-        // Check global cache flag - if disabled, use system allocator
-        if !GLOBAL_CACHE_ENABLED.load(Ordering::Relaxed) {
-            deallocate(ptr as *mut c_void, layout.size());
+        #[cfg(feature = "track_allocations")]
+        let track_system = || {
+            track(tracker::Event::Free {
+                addr: ptr as usize,
+                size: layout.size(),
+                action: tracker::Action::System,
+            })
+        };
+
+        let Some(class) = class_for(layout) else {
+            #[cfg(feature = "track_allocations")]
+            track_system();
+            stats::free_shared(None, layout.size());
+            unsafe { self.unmap(ptr, block_size(layout), layout.align()) };
+            return;
+        };
+
+        if !Self::heap_usable() {
+            #[cfg(feature = "track_allocations")]
+            track_system();
+            Self::count_fallback();
+            unsafe { self.dealloc_uncached(ptr, layout) };
             return;
         }
 
-        let result = CURRENT_THREAD_ALLOCATOR.try_with(|state| unsafe {
-            let state = &mut *state.get();
-            if state.size < state.free_array.len() {
+        let capacity = Self::cache_capacity(config, class);
+        let result = with_thread_heap(config.never_unmap, |state| unsafe {
+            state.register();
+            if class < NUM_SMALL_CLASSES && !state.slabs.owns(ptr) {
+                // Slots of other threads go back to their owner instead of into our cache
                 #[cfg(feature = "track_allocations")]
-                {
-                    use crate::tracker::Action;
-                    let _ = THREAD_TRACKER.try_with(|tracker| {
-                        let tracker = &mut *tracker.get();
-                        tracker.track(tracker::Event::Free {
-                            addr: ptr as usize,
-                            size: layout.size() as usize,
-                            action: Action::Cache,
-                        });
-                    });
-                }
-                state.insert(Block {
+                track(tracker::Event::Free {
+                    addr: ptr as usize,
                     size: layout.size(),
-                    ptr,
+                    action: tracker::Action::Remote,
+                });
+                state.stats.free(Some(class), class_size(class));
+                slab::free_remote(ptr);
+                return true;
+            }
+            state.tick(config);
+            // The block has the size of its class, so it can be reused for the whole class
+            let mut cached = state.bins.insert(class, ptr, capacity);
+            if !cached && state.bins.bins[class].len > 0 {
+                // Make room by giving back the blocks of this class that were cached the longest
+                state.evict(class, slab::batch_size(class));
+                cached = state.bins.insert(class, ptr, capacity);
+            }
+            if !cached && class < NUM_SMALL_CLASSES {
+                #[cfg(feature = "track_allocations")]
+                track(tracker::Event::Free {
+                    addr: ptr as usize,
+                    size: layout.size(),
+                    action: tracker::Action::Slab,
+                });
+                state.stats.free(Some(class), class_size(class));
+                state.slabs.give_back_one(class, ptr);
+                return true;
+            }
+            if cached {
+                state.stats.free(Some(class), class_size(class));
+            }
+            #[cfg(feature = "track_allocations")]
+            if cached {
+                track(tracker::Event::Free {
+                    addr: ptr as usize,
+                    size: layout.size(),
+                    action: tracker::Action::Cache,
                 });
-                true // Cached
-            } else {
-                false // Need to deallocate
             }
+            cached
         });
 
         match result {
@@ -238,27 +989,176 @@ unsafe impl GlobalAlloc for BeneAlloc {
                 // Successfully cached in free list
             }
             Ok(false) => {
-                // Free list is full, deallocate via system
+                // Free list is full, give the block back to the slab or the OS
                 #[cfg(feature = "track_allocations")]
-                {
-                    use crate::tracker::Action;
-                    let _ = THREAD_TRACKER.try_with(|tracker| {
-                        let tracker = &mut *tracker.get();
-                        tracker.track(tracker::Event::Free {
-                            addr: ptr as usize,
-                            size: layout.size() as usize,
-                            action: Action::System,
-                        });
-                    });
-                }
-                deallocate(ptr as *mut c_void, layout.size());
+                track_system();
+                unsafe { self.dealloc_uncached(ptr, layout) };
             }
             Err(_) => {
-                // Thread-local is being destroyed, disable cache globally and fallback to system
-                GLOBAL_CACHE_ENABLED.store(false, Ordering::Relaxed);
-                deallocate(ptr as *mut c_void, layout.size());
+                // Thread-local is already destroyed, free without it
+                #[cfg(feature = "track_allocations")]
+                track_system();
+                Self::count_fallback();
+                unsafe { self.dealloc_uncached(ptr, layout) };
+            }
+        }
+    }
+
+    /// Returns how many blocks of `class` may be cached by a thread at most.
+    fn cache_capacity(config: &Builder, class: usize) -> usize {
+        if class < NUM_SMALL_CLASSES {
+            config.thread_cache_capacity
+        } else if config.never_unmap {
+            // Keeping a mapping around is better than leaking it
+            usize::MAX
+        } else if class_size(class) > config.max_cached_block {
+            0
+        } else {
+            config.thread_cache_capacity
+        }
+    }
+}
+
+#[cfg(feature = "track_allocations")]
+fn track(event: tracker::Event) {
+    // Failed allocations leave no record
+    if let tracker::Event::Alloc { addr: 0, .. } = event {
+        return;
+    }
+    // Registering the destructor of the tracker may allocate, which is tracked as well. Those
+    // records are written right away instead of recursing into the registration.
+    let started = TRACKER_STATE
+        .try_with(|state| match state.get() {
+            ThreadState::Active => true,
+            ThreadState::New => {
+                state.set(ThreadState::Starting);
+                let started = THREAD_TRACKER.try_with(|_| {}).is_ok();
+                state.set(ThreadState::Active);
+                started
+            }
+            ThreadState::Starting | ThreadState::TearingDown => false,
+        })
+        .unwrap_or(false);
+    if !started
+        || THREAD_TRACKER
+            .try_with(|tracker| unsafe {
+                let tracker = &mut *tracker.get();
+                tracker.track(event);
+            })
+            .is_err()
+    {
+        tracker::track_unbuffered(event);
+    }
+}
+
+unsafe impl GlobalAlloc for BeneAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.alloc_block(padded(layout)).0;
+        #[cfg(feature = "hardened")]
+        hardened::allocated(ptr, layout);
+        #[cfg(feature = "heap_profile")]
+        profile::allocated(ptr, layout.size());
+        #[cfg(feature = "leak_check")]
+        leaks::allocated(ptr, layout.size());
+        ptr
+    }
+
+    /// Memory fresh from the OS is zero already, so only recycled blocks have to be cleared.
+    /// This keeps large zeroed allocations lazily committed by the kernel.
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let (ptr, zeroed) = self.alloc_block(padded(layout));
+        #[cfg(feature = "heap_profile")]
+        profile::allocated(ptr, layout.size());
+        #[cfg(feature = "leak_check")]
+        leaks::allocated(ptr, layout.size());
+        if !ptr.is_null() {
+            unsafe { zeroed.clear(ptr, layout.size()) };
+        }
+        // Clearing the link may have overwritten the canary of a tiny block
+        #[cfg(feature = "hardened")]
+        hardened::allocated(ptr, layout);
+        ptr
+    }
+
+    /// The caller must ensure the ptr and layout are valid, so we do not have to keep track of
+    /// how much memory was allocated for a given pointer. This helps us, because we do not have to
+    /// modify the allocated list in other threads, which would require some kind of synchronization.
+    /// Instead, we can add it to the local `free` list or give it back to the slab or the OS.
+    ///
+    /// # Safety
+    /// The caller must ensure ptr and layout are valid. Additionally, the ptr may not be used after this function is called as any use would be UAF
+    /// The caller must ensure the ptr was allocated by this allocator. Other allocators used(say for C libraries) do need to be deallocated by
+    /// that allocator as to not corrupt this allocator's state
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "hardened")]
+        hardened::freed(ptr, layout);
+        #[cfg(feature = "heap_profile")]
+        profile::freed(ptr);
+        #[cfg(feature = "leak_check")]
+        leaks::freed(ptr);
+        let layout = padded(layout);
+        // Sized free: the layout picks the bin and the address the segment, nothing about the
+        // block is looked up
+        if let Some(class) = class_for(layout)
+            && class < NUM_SMALL_CLASSES
+            && unsafe { self.free_cached(ptr, class) }
+        {
+            #[cfg(feature = "track_allocations")]
+            track(tracker::Event::Free {
+                addr: ptr as usize,
+                size: layout.size(),
+                action: tracker::Action::Cache,
+            });
+            return;
+        }
+        unsafe { self.dealloc_slow(ptr, layout) };
+    }
+
+    /// Resizes in place where possible: blocks have the size of their class, so a resize within
+    /// the class is free, and blocks with a mapping of their own are moved by the kernel with
+    /// mremap. Only everything else is copied into a new block.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // The trait guarantees that the new size, rounded up to the alignment, fits into an isize
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        #[cfg(feature = "hardened")]
+        hardened::resizing(ptr, layout);
+
+        let old_class = class_for(padded(layout));
+        let new_ptr = if old_class.is_some() && old_class == class_for(padded(new_layout)) {
+            ptr
+        } else {
+            std::ptr::null_mut()
+        };
+        #[cfg(target_os = "linux")]
+        // Remapping would give the pages of the old block back to the OS
+        let new_ptr = if new_ptr.is_null() && !self.config().never_unmap {
+            unsafe { Self::remap(ptr, layout, new_layout) }
+        } else {
+            new_ptr
+        };
+        if !new_ptr.is_null() {
+            #[cfg(feature = "track_allocations")]
+            track(tracker::Event::Resize {
+                addr: ptr as usize,
+                new_addr: new_ptr as usize,
+                new_size,
+            });
+            #[cfg(feature = "hardened")]
+            hardened::resized(ptr, layout, new_ptr, new_layout);
+            #[cfg(feature = "heap_profile")]
+            profile::resized(ptr, new_ptr, new_size);
+            #[cfg(feature = "leak_check")]
+            leaks::resized(ptr, new_ptr, new_size);
+            return new_ptr;
+        }
+
+        let new_ptr = unsafe { self.alloc(new_layout) };
+        if !new_ptr.is_null() {
+            unsafe {
+                std::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
             }
         }
+        new_ptr
     }
-    // TODO: On windows alloc_zeroed initializes the memory to be zero so we could save performance by skipping directly to malloc if we need it...
 }
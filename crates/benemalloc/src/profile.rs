@@ -0,0 +1,399 @@
+//! A sampling heap profiler, with the `heap_profile` feature.
+//!
+//! Allocations are sampled at random distances of on average [`sample_rate`] bytes, so large
+//! allocations are more likely to be sampled than small ones. A sample records the return
+//! addresses of the allocating stack, and is scaled so that the samples of a stack estimate
+//! everything it allocated. Sampled blocks are tracked until they are freed, so a profile shows
+//! both what is allocated right now and what was allocated since the program started.
+//!
+//! The rate is set with `BENEMALLOC_PROFILE_RATE`, 512 KiB by default. `0` turns the profiler off.
+//! Smaller rates are more accurate and slower.
+//!
+//! [`write_pprof`] exports the profile in the protobuf format of [pprof], with the same sample
+//! types as the heap profiles of Go. pprof symbolizes the addresses with the binary:
+//!
+//! ```text
+//! pprof -sample_index=inuse_space -top ./target/release/my-binary heap.pb
+//! ```
+//!
+//! [pprof]: https://github.com/google/pprof
+
+use crate::backtrace::{self, MAX_FRAMES, MAX_STACKS, Stack, Stacks};
+use crate::env;
+use crate::spin::SpinLock;
+use crate::table::AddrMap;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const FILTER_BITS: u32 = 16;
+
+thread_local! {
+    // Bytes left until the next sample. The first allocation of a thread draws the distance.
+    static UNTIL_SAMPLE: Cell<isize> = const { Cell::new(0) };
+    static RNG: Cell<u64> = const { Cell::new(0) };
+    // Set while the profiler works on this thread, so its own allocations are not sampled
+    static BUSY: Cell<bool> = const { Cell::new(false) };
+}
+
+static TABLES: SpinLock<Tables> = SpinLock::new(Tables {
+    stacks: Stacks::new(),
+    totals: [Totals::ZERO; MAX_STACKS],
+    live: AddrMap::new(),
+});
+// How many live samples hash to each slot. Frees of blocks whose slot is empty, which is almost
+// all of them, don't have to take the lock.
+static FILTER: [AtomicU32; 1 << FILTER_BITS] = [const { AtomicU32::new(0) }; 1 << FILTER_BITS];
+
+/// Returns the average distance between samples in bytes, 0 if the profiler is off.
+pub fn sample_rate() -> usize {
+    env::options().profile_rate
+}
+
+// Estimates of what a stack allocated
+#[derive(Copy, Clone)]
+struct Totals {
+    alloc_objects: f64,
+    alloc_bytes: f64,
+    live_objects: f64,
+    live_bytes: f64,
+}
+
+impl Totals {
+    const ZERO: Self = Self {
+        alloc_objects: 0.0,
+        alloc_bytes: 0.0,
+        live_objects: 0.0,
+        live_bytes: 0.0,
+    };
+}
+
+// A sampled block that is not freed yet
+#[derive(Copy, Clone)]
+struct Live {
+    stack: usize,
+    objects: f64,
+    bytes: f64,
+}
+
+struct Tables {
+    stacks: Stacks,
+    // Indexed like the stacks
+    totals: [Totals; MAX_STACKS],
+    live: AddrMap<Live>,
+}
+
+fn slot(addr: usize) -> usize {
+    ((addr as u64 >> 3).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (64 - FILTER_BITS)) as usize
+}
+
+/// Counts an allocation towards the next sample.
+#[inline]
+pub(crate) fn allocated(ptr: *mut u8, size: usize) {
+    let left = UNTIL_SAMPLE.get() - size as isize;
+    UNTIL_SAMPLE.set(left);
+    if left <= 0 && !ptr.is_null() {
+        sample(ptr, size);
+    }
+}
+
+/// Removes a block from the profile if it was sampled.
+#[inline]
+pub(crate) fn freed(ptr: *mut u8) {
+    if FILTER[slot(ptr as usize)].load(Ordering::Relaxed) != 0 {
+        unsample(ptr);
+    }
+}
+
+/// Moves a sampled block that was resized in place or remapped.
+#[inline]
+pub(crate) fn resized(ptr: *mut u8, new_ptr: *mut u8, new_size: usize) {
+    if FILTER[slot(ptr as usize)].load(Ordering::Relaxed) != 0 {
+        resample(ptr, new_ptr, new_size);
+    }
+}
+
+#[cold]
+fn sample(ptr: *mut u8, size: usize) {
+    let rate = sample_rate();
+    if rate == 0 {
+        UNTIL_SAMPLE.set(isize::MAX);
+        return;
+    }
+    if RNG.get() == 0 {
+        // Seed every thread differently, and skip the sample: the first allocation of a thread
+        // only draws the distance to its first sample
+        let seed = &RNG as *const _ as u64
+            ^ SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_nanos() as u64);
+        RNG.set(seed | 1);
+        UNTIL_SAMPLE.set(distance(rate));
+        return;
+    }
+    UNTIL_SAMPLE.set(distance(rate));
+    if BUSY.replace(true) {
+        return;
+    }
+    let mut frames = [0; MAX_FRAMES];
+    let depth = backtrace::capture(&mut frames);
+    // An allocation of `size` bytes is sampled with a probability of 1 - e^(-size / rate)
+    let scale = 1.0 / (1.0 - (-(size.max(1) as f64) / rate as f64).exp());
+    record(ptr as usize, &frames[..depth], scale, size as f64 * scale);
+    BUSY.set(false);
+}
+
+fn record(addr: usize, frames: &[usize], objects: f64, bytes: f64) {
+    let mut tables = TABLES.lock();
+    // Samples are dropped once the stacks or the memory for the live blocks run out
+    let Some(stack) = tables.stacks.intern(frames) else {
+        return;
+    };
+    let live = Live {
+        stack,
+        objects,
+        bytes,
+    };
+    let tracked = tables.live.insert(addr, live);
+    let totals = &mut tables.totals[stack];
+    totals.alloc_objects += objects;
+    totals.alloc_bytes += bytes;
+    if tracked {
+        totals.live_objects += objects;
+        totals.live_bytes += bytes;
+        FILTER[slot(addr)].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cold]
+fn unsample(ptr: *mut u8) {
+    let mut tables = TABLES.lock();
+    if let Some(live) = tables.live.remove(ptr as usize) {
+        FILTER[slot(ptr as usize)].fetch_sub(1, Ordering::Relaxed);
+        let totals = &mut tables.totals[live.stack];
+        totals.live_objects -= live.objects;
+        totals.live_bytes -= live.bytes;
+    }
+}
+
+#[cold]
+fn resample(ptr: *mut u8, new_ptr: *mut u8, new_size: usize) {
+    let mut tables = TABLES.lock();
+    let Some(mut live) = tables.live.remove(ptr as usize) else {
+        return;
+    };
+    FILTER[slot(ptr as usize)].fetch_sub(1, Ordering::Relaxed);
+    tables.totals[live.stack].live_bytes -= live.bytes;
+    // The block still stands for as many objects, which now have the new size
+    live.bytes = live.objects * new_size as f64;
+    if tables.live.insert(new_ptr as usize, live) {
+        tables.totals[live.stack].live_bytes += live.bytes;
+        FILTER[slot(new_ptr as usize)].fetch_add(1, Ordering::Relaxed);
+    } else {
+        tables.totals[live.stack].live_objects -= live.objects;
+    }
+}
+
+// Draws the distance to the next sample from an exponential distribution with the mean `rate`
+fn distance(rate: usize) -> isize {
+    // xorshift64
+    let mut x = RNG.get();
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    RNG.set(x);
+    // Uniform in (0, 1]
+    let uniform = ((x >> 11) + 1) as f64 / (1u64 << 53) as f64;
+    (-uniform.ln() * rate as f64) as isize + 1
+}
+
+/// Writes the heap profile in the protobuf format of pprof.
+///
+/// The profile has the sample types `alloc_objects` and `alloc_space` for everything allocated
+/// so far, and `inuse_objects` and `inuse_space` for what is allocated right now, which is the
+/// default. Samples have the return addresses of their stack, which pprof symbolizes.
+pub fn write_pprof<W: Write>(mut writer: W) -> io::Result<()> {
+    let busy = BUSY.replace(true);
+    let result = encode().and_then(|profile| writer.write_all(&profile));
+    BUSY.set(busy);
+    result
+}
+
+fn encode() -> io::Result<Vec<u8>> {
+    let stacks: Vec<(Stack, Totals)> = {
+        let tables = TABLES.lock();
+        // Allocating here doesn't sample, so it can't come back for the lock
+        let mut stacks = Vec::with_capacity(MAX_STACKS);
+        tables
+            .stacks
+            .for_each(|index, stack| stacks.push((*stack, tables.totals[index])));
+        stacks
+    };
+    let mappings = mappings();
+
+    let mut strings = Strings::default();
+    let mut profile = Vec::new();
+    for (kind, unit) in [
+        ("alloc_objects", "count"),
+        ("alloc_space", "bytes"),
+        ("inuse_objects", "count"),
+        ("inuse_space", "bytes"),
+    ] {
+        let value_type = value_type(&mut strings, kind, unit);
+        message(&mut profile, 1, &value_type);
+    }
+
+    let mut locations: Vec<u64> = Vec::new();
+    let mut location_ids: HashMap<u64, u64> = HashMap::new();
+    for (stack, totals) in &stacks {
+        let mut ids = Vec::new();
+        for &frame in stack.frames() {
+            // Return addresses point after the call, pprof wants the call itself
+            let address = frame as u64 - 1;
+            let id = *location_ids.entry(address).or_insert_with(|| {
+                locations.push(address);
+                locations.len() as u64
+            });
+            varint(&mut ids, id);
+        }
+        let mut values = Vec::new();
+        for value in [
+            totals.alloc_objects,
+            totals.alloc_bytes,
+            totals.live_objects,
+            totals.live_bytes,
+        ] {
+            varint(&mut values, value.round().max(0.0) as u64);
+        }
+        let mut sample = Vec::new();
+        message(&mut sample, 1, &ids);
+        message(&mut sample, 2, &values);
+        message(&mut profile, 2, &sample);
+    }
+
+    for (index, mapping) in mappings.iter().enumerate() {
+        let mut encoded = Vec::new();
+        field(&mut encoded, 1, index as u64 + 1);
+        field(&mut encoded, 2, mapping.start);
+        field(&mut encoded, 3, mapping.limit);
+        field(&mut encoded, 4, mapping.offset);
+        field(&mut encoded, 5, strings.index(&mapping.file));
+        message(&mut profile, 3, &encoded);
+    }
+    for (index, &address) in locations.iter().enumerate() {
+        let mut location = Vec::new();
+        field(&mut location, 1, index as u64 + 1);
+        if let Some(mapping) = mappings
+            .iter()
+            .position(|mapping| (mapping.start..mapping.limit).contains(&address))
+        {
+            field(&mut location, 2, mapping as u64 + 1);
+        }
+        field(&mut location, 3, address);
+        message(&mut profile, 4, &location);
+    }
+
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(io::Error::other)?;
+    field(&mut profile, 9, time.as_nanos() as u64);
+    let period_type = value_type(&mut strings, "space", "bytes");
+    message(&mut profile, 11, &period_type);
+    field(&mut profile, 12, sample_rate() as u64);
+    let default = strings.index("inuse_space");
+    field(&mut profile, 14, default);
+    // The string table goes last, so it has every string
+    for string in &strings.0 {
+        message(&mut profile, 6, string.as_bytes());
+    }
+    Ok(profile)
+}
+
+struct Mapping {
+    start: u64,
+    limit: u64,
+    offset: u64,
+    file: String,
+}
+
+// The executable mappings of files, which pprof needs to symbolize addresses
+#[cfg(target_os = "linux")]
+fn mappings() -> Vec<Mapping> {
+    let maps = std::fs::read_to_string("/proc/self/maps").unwrap_or_default();
+    maps.lines()
+        .filter_map(|line| {
+            // start-limit perms offset device inode file
+            let mut fields = line.split_whitespace();
+            let (start, limit) = fields.next()?.split_once('-')?;
+            if !fields.next()?.contains('x') {
+                return None;
+            }
+            let offset = fields.next()?;
+            let file = fields.nth(2)?;
+            if !file.starts_with('/') {
+                return None;
+            }
+            Some(Mapping {
+                start: u64::from_str_radix(start, 16).ok()?,
+                limit: u64::from_str_radix(limit, 16).ok()?,
+                offset: u64::from_str_radix(offset, 16).ok()?,
+                file: file.to_string(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn mappings() -> Vec<Mapping> {
+    Vec::new()
+}
+
+// The string table of a profile, which starts with the empty string
+#[derive(Default)]
+struct Strings(Vec<String>);
+
+impl Strings {
+    fn index(&mut self, string: &str) -> u64 {
+        if self.0.is_empty() {
+            self.0.push(String::new());
+        }
+        let index = match self.0.iter().position(|known| known == string) {
+            Some(index) => index,
+            None => {
+                self.0.push(string.to_string());
+                self.0.len() - 1
+            }
+        };
+        index as u64
+    }
+}
+
+fn value_type(strings: &mut Strings, kind: &str, unit: &str) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    field(&mut encoded, 1, strings.index(kind));
+    field(&mut encoded, 2, strings.index(unit));
+    encoded
+}
+
+fn varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+// A varint field
+fn field(buf: &mut Vec<u8>, number: u64, value: u64) {
+    varint(buf, number << 3);
+    varint(buf, value);
+}
+
+// A length-delimited field: strings, nested messages and packed repeated varints
+fn message(buf: &mut Vec<u8>, number: u64, bytes: &[u8]) {
+    varint(buf, (number << 3) | 2);
+    varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
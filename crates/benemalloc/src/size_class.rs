@@ -0,0 +1,115 @@
+//! Size classes used to bin freed blocks.
+//!
+//! Sizes up to 64 bytes are spaced 8 bytes apart. Above that every power of two is split into
+//! four classes, so a block never serves a request that is more than 25% smaller than itself.
+
+use std::alloc::Layout;
+
+/// Sizes up to this are spaced linearly in steps of [`MIN_SIZE`]
+const LINEAR_MAX: usize = 64;
+const MIN_SIZE: usize = 8;
+/// The largest size that is still binned. Anything bigger is mapped and unmapped directly.
+pub(crate) const MAX_BINNED_SIZE: usize = 64 << 20;
+pub(crate) const NUM_CLASSES: usize = class_index(MAX_BINNED_SIZE) + 1;
+
+/// Returns the index of the smallest class that can hold `size` bytes.
+pub(crate) const fn class_index(size: usize) -> usize {
+    if size <= LINEAR_MAX {
+        return size.saturating_sub(1) / MIN_SIZE;
+    }
+    let s = size - 1;
+    // floor(log2(size - 1)), which is at least 6 here
+    let bit = (usize::BITS - 1 - s.leading_zeros()) as usize;
+    // The two bits below the leading one select one of the four classes of this power of two
+    let sub = (s >> (bit - 2)) & 3;
+    LINEAR_MAX / MIN_SIZE + (bit - 6) * 4 + sub
+}
+
+/// Returns the size of every block in the class `index`.
+pub(crate) const fn class_size(index: usize) -> usize {
+    let linear = LINEAR_MAX / MIN_SIZE;
+    if index < linear {
+        return (index + 1) * MIN_SIZE;
+    }
+    let bit = 6 + (index - linear) / 4;
+    let sub = (index - linear) % 4;
+    (1 << bit) + (sub + 1) * (1 << (bit - 2))
+}
+
+/// Returns the class for a block of `size` bytes, or `None` if blocks that large are not binned.
+#[inline]
+pub(crate) const fn class_of(size: usize) -> Option<usize> {
+    if size > MAX_BINNED_SIZE {
+        None
+    } else {
+        Some(class_index(size))
+    }
+}
+
+/// Returns the class serving `layout`, or `None` if it is too large to be binned.
+///
+/// The size is first rounded up to the alignment. The resulting class size is then a multiple of
+/// the alignment, which keeps slots that are aligned to their own size aligned to the layout.
+#[inline]
+pub(crate) const fn class_for(layout: Layout) -> Option<usize> {
+    class_of(layout.pad_to_align().size())
+}
+
+/// Returns the number of bytes actually backing an allocation of `layout`.
+///
+/// Every path that maps, caches or releases a block goes through this, so a block is always
+/// released or recycled with its full extent, even when it serves a smaller request.
+#[inline]
+pub(crate) const fn block_size(layout: Layout) -> usize {
+    match class_for(layout) {
+        Some(class) => class_size(class),
+        None => layout.size(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::slab::{NUM_SMALL_CLASSES, SMALL_MAX};
+
+    #[test]
+    fn linear_classes() {
+        assert_eq!(class_index(0), 0);
+        assert_eq!(class_index(1), 0);
+        assert_eq!(class_index(8), 0);
+        assert_eq!(class_index(9), 1);
+        assert_eq!(class_index(64), 7);
+        assert_eq!(class_size(0), 8);
+        assert_eq!(class_size(7), 64);
+        // The first class above the linear ones is a quarter larger
+        assert_eq!(class_index(65), 8);
+        assert_eq!(class_size(8), 80);
+    }
+
+    #[test]
+    fn class_edges() {
+        for class in 0..NUM_CLASSES {
+            let size = class_size(class);
+            assert_eq!(class_index(size), class, "{size} bytes");
+            assert_eq!(class_index(size + 1), class + 1, "{} bytes", size + 1);
+            if class > 0 {
+                assert!(class_size(class - 1) < size);
+            }
+            if size > LINEAR_MAX {
+                // No request the class serves is more than 25% smaller than the block
+                assert!((size - class_size(class - 1)) * 4 <= size);
+            }
+        }
+        assert_eq!(class_size(NUM_CLASSES - 1), MAX_BINNED_SIZE);
+        assert_eq!(class_of(MAX_BINNED_SIZE), Some(NUM_CLASSES - 1));
+        assert_eq!(class_of(MAX_BINNED_SIZE + 1), None);
+    }
+
+    #[test]
+    fn small_classes() {
+        assert_eq!(class_size(class_index(SMALL_MAX)), SMALL_MAX);
+        assert_eq!(class_index(SMALL_MAX), NUM_SMALL_CLASSES - 1);
+        assert_eq!(class_index(SMALL_MAX + 1), NUM_SMALL_CLASSES);
+        assert_eq!(class_size(NUM_SMALL_CLASSES), SMALL_MAX + SMALL_MAX / 4);
+    }
+}
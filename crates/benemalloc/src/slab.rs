@@ -0,0 +1,668 @@
+//! Slabs carve segments mapped from the OS into slots of a single size class, so small objects
+//! don't cost a mapping and a syscall each.
+//!
+//! Every segment is owned by one heap, usually the one of a thread. Only the owner takes slots
+//! from a segment or puts them back, which needs no synchronization. Other threads that free a slot
+//! push it onto the lock-free remote list of its segment instead, and the owner takes those slots
+//! back once it runs out of free ones. Memory therefore stays with the thread that allocated it,
+//! and a consumer thread doesn't pile up the memory of its producers.
+//! A segment is given back to the OS once all of its slots are free again. The last one of a class
+//! is kept for the next allocation, until it went unused for a whole decay of its heap.
+//!
+//! When a heap goes away, e.g. because its thread exited, its segments are abandoned. Other heaps
+//! adopt them before mapping new ones, so the slots still in use elsewhere aren't stuck forever.
+
+use crate::size_class::{class_index, class_size};
+use crate::spin::SpinLock;
+use crate::{FreeBlock, Zeroed};
+use crate::{arena, stats};
+use allocations::{allocate_aligned, deallocate_aligned, page_size, purge};
+use std::ffi::c_void;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+/// Segments are aligned to their size, so the segment of a slot is found by masking its address.
+pub(crate) const SEGMENT_SIZE: usize = 256 * 1024;
+/// The largest size carved from segments. Larger blocks get a mapping of their own.
+pub(crate) const SMALL_MAX: usize = 16 * 1024;
+pub(crate) const NUM_SMALL_CLASSES: usize = class_index(SMALL_MAX) + 1;
+
+// Owner of segments that belong to no heap
+const NO_OWNER: usize = 0;
+// Owner of the segments of the global heap
+const GLOBAL_OWNER: usize = 1;
+static NEXT_OWNER: AtomicUsize = AtomicUsize::new(GLOBAL_OWNER + 1);
+
+/// The heap of threads that can't use their own, e.g. while it is being torn down.
+static GLOBAL: SpinLock<Slabs> = SpinLock::new(Slabs::with_owner(GLOBAL_OWNER));
+
+/// Segments of heaps that went away, per class, waiting to be adopted. Segments bumped from the
+/// arena are only adopted by heaps that never unmap memory either, so they are kept apart.
+static ABANDONED: [SpinLock<[SegmentList; NUM_SMALL_CLASSES]>; 2] =
+    [const { SpinLock::new([const { SegmentList::new() }; NUM_SMALL_CLASSES]) }; 2];
+
+// Sits at the start of every segment, the slots follow after it.
+// Everything but `owner` and `remote` is only touched by the owning heap.
+#[repr(C)]
+struct Segment {
+    class: usize,
+    // Offset of the first slot. It is a multiple of the slot size, which makes every slot aligned
+    // to the largest power of two that divides the slot size.
+    first: usize,
+    capacity: usize,
+    // Slots handed out, including the ones waiting in thread caches or on the remote list
+    used: usize,
+    // Slots below this index have been handed out at least once, the ones above were never touched
+    carved: usize,
+    free: *mut FreeBlock,
+    // Whether the segment is in the full list of its class instead of the available one
+    full: bool,
+    // Whether the segment was empty at the last decay, and no slot was taken from it since
+    idle: bool,
+    prev: *mut Segment,
+    next: *mut Segment,
+    owner: AtomicUsize,
+    // Slots freed by other threads, waiting for the owner to take them back
+    remote: AtomicPtr<FreeBlock>,
+}
+
+// The owner only works on segments through raw pointers, so other threads can access the atomic
+// fields at the same time without a reference to the whole segment being alive.
+impl Segment {
+    fn of(ptr: *mut u8) -> *mut Segment {
+        (ptr as usize & !(SEGMENT_SIZE - 1)) as *mut Segment
+    }
+
+    /// Maps a new segment for `class`, returns null if the OS is out of memory.
+    /// Segments that are never unmapped are bumped from the arena instead.
+    fn map(class: usize, owner: usize, never_unmap: bool) -> *mut Segment {
+        let base = if never_unmap {
+            arena::bump(SEGMENT_SIZE, SEGMENT_SIZE) as *mut c_void
+        } else {
+            allocate_aligned(SEGMENT_SIZE, SEGMENT_SIZE)
+        };
+        if base.is_null() {
+            return null_mut();
+        }
+        if !never_unmap {
+            stats::mapped(SEGMENT_SIZE);
+        }
+        // A guard page at the end keeps the last slot from running into the next segment
+        #[cfg(feature = "hardened")]
+        let end = unsafe { crate::hardened::guard_tail(base as *mut u8, SEGMENT_SIZE) };
+        #[cfg(not(feature = "hardened"))]
+        let end = SEGMENT_SIZE;
+        let size = class_size(class);
+        let first = size_of::<Segment>().next_multiple_of(size);
+        let segment = base as *mut Segment;
+        unsafe {
+            segment.write(Segment {
+                class,
+                first,
+                capacity: (end - first) / size,
+                used: 0,
+                carved: 0,
+                free: null_mut(),
+                full: false,
+                idle: false,
+                prev: null_mut(),
+                next: null_mut(),
+                owner: AtomicUsize::new(owner),
+                remote: AtomicPtr::new(null_mut()),
+            });
+        }
+        segment
+    }
+
+    /// # Safety
+    /// The segment must be owned by the caller and all of its slots must be free.
+    unsafe fn release(segment: *mut Segment) {
+        unsafe { deallocate_aligned(segment as *mut c_void, SEGMENT_SIZE, SEGMENT_SIZE) };
+        stats::unmapped(SEGMENT_SIZE);
+    }
+
+    /// Gives the pages of the slots back to the OS but keeps the segment mapped, for segments
+    /// bumped from the arena. The slots are carved anew from the zeroed pages afterwards.
+    ///
+    /// # Safety
+    /// The segment must be owned by the caller and all of its slots must be free.
+    unsafe fn purge(segment: *mut Segment) {
+        unsafe {
+            if (*segment).carved == 0 {
+                // No slot was touched since the segment was mapped or purged
+                return;
+            }
+            let page = page_size();
+            let start = segment as usize + (*segment).first;
+            let end = start + (*segment).capacity * class_size((*segment).class);
+            // The header shares the first page with slots, and the guard page of the hardened
+            // mode must stay as it is
+            let (from, to) = (start.next_multiple_of(page), end & !(page - 1));
+            if from >= to || !purge(from as *mut c_void, to - from) {
+                return;
+            }
+            (start as *mut u8).write_bytes(0, from - start);
+            (to as *mut u8).write_bytes(0, end - to);
+            (*segment).free = null_mut();
+            (*segment).carved = 0;
+        }
+    }
+
+    /// Returns a free slot and whether it was never used before, in which case it is still zero.
+    ///
+    /// # Safety
+    /// The segment must be owned by the caller and have a free slot.
+    unsafe fn pop(segment: *mut Segment) -> (*mut u8, bool) {
+        unsafe {
+            debug_assert!((*segment).used < (*segment).capacity);
+            (*segment).used += 1;
+            (*segment).idle = false;
+            let block = (*segment).free;
+            if !block.is_null() {
+                (*segment).free = (*block).next();
+                return (block as *mut u8, false);
+            }
+            let offset = (*segment).first + (*segment).carved * class_size((*segment).class);
+            (*segment).carved += 1;
+            ((segment as *mut u8).add(offset), true)
+        }
+    }
+
+    /// # Safety
+    /// The segment must be owned by the caller and ptr must be one of its handed out slots.
+    unsafe fn push(segment: *mut Segment, ptr: *mut u8) {
+        unsafe {
+            FreeBlock::init(ptr as *mut FreeBlock, (*segment).free, false);
+            (*segment).free = ptr as *mut FreeBlock;
+            (*segment).used -= 1;
+        }
+    }
+
+    /// Takes back the slots other threads freed. Returns whether there were any.
+    ///
+    /// # Safety
+    /// The segment must be owned by the caller.
+    unsafe fn drain_remote(segment: *mut Segment) -> bool {
+        unsafe {
+            if (*segment).remote.load(Ordering::Relaxed).is_null() {
+                return false;
+            }
+            let mut block = (*segment).remote.swap(null_mut(), Ordering::Acquire);
+            while !block.is_null() {
+                let next = (*block).next();
+                Segment::push(segment, block as *mut u8);
+                block = next;
+            }
+        }
+        true
+    }
+}
+
+// An intrusive doubly linked list of segments.
+struct SegmentList {
+    head: *mut Segment,
+}
+
+// The links of a segment are only touched by whoever holds the list it is in
+unsafe impl Send for SegmentList {}
+
+impl SegmentList {
+    const fn new() -> Self {
+        Self { head: null_mut() }
+    }
+
+    unsafe fn link(&mut self, segment: *mut Segment) {
+        unsafe {
+            (*segment).prev = null_mut();
+            (*segment).next = self.head;
+            if !self.head.is_null() {
+                (*self.head).prev = segment;
+            }
+        }
+        self.head = segment;
+    }
+
+    unsafe fn unlink(&mut self, segment: *mut Segment) {
+        unsafe {
+            let (prev, next) = ((*segment).prev, (*segment).next);
+            if prev.is_null() {
+                self.head = next;
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+        }
+    }
+}
+
+// The segments of one class. Full segments are kept apart so allocating never has to skip them,
+// and go back to the available ones once a slot of theirs is freed.
+struct SlabClass {
+    avail: SegmentList,
+    full: SegmentList,
+    // The segment slots were taken from last, null once it is released or handed over
+    recent: *mut Segment,
+}
+
+impl SlabClass {
+    const fn new() -> Self {
+        Self {
+            avail: SegmentList::new(),
+            full: SegmentList::new(),
+            recent: null_mut(),
+        }
+    }
+
+    // Releases a segment of the class that was unlinked already. Its address may be mapped by
+    // another heap next, so it can't stay the recent one.
+    unsafe fn release(&mut self, segment: *mut Segment) {
+        if self.recent == segment {
+            self.recent = null_mut();
+        }
+        unsafe { Segment::release(segment) };
+    }
+
+    unsafe fn mark_available(&mut self, segment: *mut Segment) {
+        unsafe {
+            if (*segment).full {
+                self.full.unlink(segment);
+                (*segment).full = false;
+                self.avail.link(segment);
+            }
+        }
+    }
+
+    unsafe fn mark_full(&mut self, segment: *mut Segment) {
+        unsafe {
+            self.avail.unlink(segment);
+            (*segment).full = true;
+            self.full.link(segment);
+        }
+    }
+
+    // Releases a segment that has no slots handed out anymore, unless memory is never unmapped.
+    // The last available segment of a class is kept, so a single object bouncing between alloc
+    // and dealloc doesn't map and unmap a segment every time.
+    unsafe fn release_if_empty(&mut self, segment: *mut Segment, never_unmap: bool) {
+        unsafe {
+            if !never_unmap
+                && (*segment).used == 0
+                && (self.avail.head != segment || !(*segment).next.is_null())
+            {
+                self.avail.unlink(segment);
+                self.release(segment);
+            }
+        }
+    }
+
+    // Adopts abandoned segments of the class until one of them has free slots.
+    unsafe fn adopt(&mut self, class: usize, owner: usize, never_unmap: bool) {
+        let mut abandoned = ABANDONED[never_unmap as usize].lock();
+        let list = &mut abandoned[class];
+        while self.avail.head.is_null() && !list.head.is_null() {
+            let segment = list.head;
+            unsafe {
+                list.unlink(segment);
+                // Remote frees keep piling up until the new owner takes them back
+                (*segment).owner.store(owner, Ordering::Relaxed);
+                Segment::drain_remote(segment);
+                (*segment).full = (*segment).used == (*segment).capacity;
+                if (*segment).full {
+                    self.full.link(segment);
+                } else {
+                    self.avail.link(segment);
+                }
+            }
+        }
+    }
+
+    // Returns a segment with free slots, taking back slots freed by other threads and adopting
+    // abandoned segments before mapping a new one. Returns null if the OS is out of memory.
+    unsafe fn available(
+        &mut self,
+        class: usize,
+        owner: usize,
+        never_unmap: bool,
+        isolated: bool,
+    ) -> *mut Segment {
+        if !self.avail.head.is_null() {
+            return self.avail.head;
+        }
+        let mut segment = self.full.head;
+        while !segment.is_null() {
+            unsafe {
+                let next = (*segment).next;
+                if Segment::drain_remote(segment) {
+                    self.mark_available(segment);
+                    self.release_if_empty(segment, never_unmap);
+                }
+                segment = next;
+            }
+        }
+        if self.avail.head.is_null() && !isolated {
+            unsafe { self.adopt(class, owner, never_unmap) };
+        }
+        if self.avail.head.is_null() {
+            let segment = Segment::map(class, owner, never_unmap);
+            if !segment.is_null() {
+                unsafe { self.avail.link(segment) };
+            }
+        }
+        self.avail.head
+    }
+}
+
+/// The slabs of one heap, with the segments of every small size class it owns.
+pub(crate) struct Slabs {
+    owner: usize,
+    /// Take segments from the arena and keep them even when all of their slots are free
+    pub(crate) never_unmap: bool,
+    // Never adopts abandoned segments, so every segment only holds slots of this heap
+    isolated: bool,
+    classes: [SlabClass; NUM_SMALL_CLASSES],
+}
+
+// The segments are only touched by whoever holds the Slabs, except for their atomic fields
+unsafe impl Send for Slabs {}
+
+impl Slabs {
+    pub(crate) const fn new() -> Self {
+        Self::with_owner(NO_OWNER)
+    }
+
+    /// Slabs of a heap that may be released as a whole, see [`Slabs::release_all`].
+    pub(crate) const fn isolated() -> Self {
+        Self {
+            isolated: true,
+            ..Self::new()
+        }
+    }
+
+    const fn with_owner(owner: usize) -> Self {
+        Self {
+            owner,
+            never_unmap: false,
+            isolated: false,
+            classes: [const { SlabClass::new() }; NUM_SMALL_CLASSES],
+        }
+    }
+
+    // The id stamped into the segments of this heap, assigned on first use
+    fn owner(&mut self) -> usize {
+        if self.owner == NO_OWNER {
+            self.owner = NEXT_OWNER.fetch_add(1, Ordering::Relaxed);
+        }
+        self.owner
+    }
+
+    /// Returns whether `ptr` is in the segment the last slots of `class` were taken from, which
+    /// belongs to this heap. Unlike [`Slabs::owns`] this doesn't read the segment.
+    #[inline]
+    pub(crate) fn recently_taken(&self, class: usize, ptr: *mut u8) -> bool {
+        Segment::of(ptr) == self.classes[class].recent
+    }
+
+    /// Returns whether the slot `ptr` belongs to a segment of this heap.
+    pub(crate) fn owns(&mut self, ptr: *mut u8) -> bool {
+        let segment = Segment::of(ptr);
+        unsafe { (*segment).owner.load(Ordering::Relaxed) == self.owner() }
+    }
+
+    /// Hands every segment over to be adopted by other heaps, for when this one goes away.
+    /// Segments without any slots in use are released instead, unless memory is never unmapped.
+    /// The slots cached by this heap must have been given back before.
+    pub(crate) fn abandon(&mut self) {
+        let mut abandoned = ABANDONED[self.never_unmap as usize].lock();
+        for (class, slab) in self.classes.iter_mut().enumerate() {
+            slab.recent = null_mut();
+            for list in [&mut slab.avail, &mut slab.full] {
+                while !list.head.is_null() {
+                    let segment = list.head;
+                    unsafe {
+                        list.unlink(segment);
+                        Segment::drain_remote(segment);
+                        if (*segment).used == 0 && !self.never_unmap {
+                            Segment::release(segment);
+                        } else {
+                            (*segment).owner.store(NO_OWNER, Ordering::Relaxed);
+                            abandoned[class].link(segment);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Releases every segment, including the slots still in use, for heaps that are destroyed.
+    /// Only isolated slabs hold nothing but their own slots.
+    pub(crate) fn release_all(&mut self) {
+        debug_assert!(self.isolated && !self.never_unmap);
+        for slab in &mut self.classes {
+            slab.recent = null_mut();
+            for list in [&mut slab.avail, &mut slab.full] {
+                while !list.head.is_null() {
+                    let segment = list.head;
+                    unsafe {
+                        list.unlink(segment);
+                        Segment::release(segment);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Releases the empty segments that were empty at the last decay already and that nothing
+    /// was taken from since. Segments that are never unmapped are kept.
+    ///
+    /// Other empty segments are released as soon as they become empty, so only the first
+    /// available one of every class is looked at.
+    pub(crate) fn decay(&mut self) {
+        if self.never_unmap {
+            return;
+        }
+        for slab in &mut self.classes {
+            let segment = slab.avail.head;
+            if segment.is_null() {
+                continue;
+            }
+            unsafe {
+                if (*segment).used > 0 {
+                    (*segment).idle = false;
+                } else if (*segment).idle {
+                    slab.avail.unlink(segment);
+                    slab.release(segment);
+                } else {
+                    (*segment).idle = true;
+                }
+            }
+        }
+    }
+
+    /// Releases every segment without slots in use, after taking back the slots other threads
+    /// freed. Segments that are never unmapped give back their pages instead.
+    pub(crate) fn release_empty(&mut self) {
+        for slab in &mut self.classes {
+            let mut segment = slab.full.head;
+            while !segment.is_null() {
+                unsafe {
+                    let next = (*segment).next;
+                    if Segment::drain_remote(segment) {
+                        slab.mark_available(segment);
+                    }
+                    segment = next;
+                }
+            }
+            let mut segment = slab.avail.head;
+            while !segment.is_null() {
+                unsafe {
+                    let next = (*segment).next;
+                    Segment::drain_remote(segment);
+                    if (*segment).used == 0 {
+                        if self.never_unmap {
+                            Segment::purge(segment);
+                        } else {
+                            slab.avail.unlink(segment);
+                            slab.release(segment);
+                        }
+                    }
+                    segment = next;
+                }
+            }
+        }
+    }
+
+    /// Takes up to `max` slots of `class`, linked into a list. Returns the head of the list and
+    /// the number of slots in it, which is only smaller than `max` if the OS is out of memory.
+    /// Slots that were never used before are marked as zeroed in the list.
+    pub(crate) fn take(&mut self, class: usize, max: usize) -> (*mut FreeBlock, usize) {
+        let owner = self.owner();
+        let slab = &mut self.classes[class];
+        let mut head = null_mut();
+        let mut count = 0;
+        unsafe {
+            if !slab.avail.head.is_null() {
+                Segment::drain_remote(slab.avail.head);
+            }
+            while count < max {
+                let segment = slab.available(class, owner, self.never_unmap, self.isolated);
+                if segment.is_null() {
+                    break;
+                }
+                let (block, zeroed) = Segment::pop(segment);
+                slab.recent = segment;
+                let block = block as *mut FreeBlock;
+                FreeBlock::init(block, head, zeroed);
+                head = block;
+                count += 1;
+                if (*segment).used == (*segment).capacity {
+                    slab.mark_full(segment);
+                }
+            }
+        }
+        (head, count)
+    }
+
+    /// Takes a single slot of `class`, returns null if the OS is out of memory.
+    pub(crate) fn take_one(&mut self, class: usize) -> (*mut u8, Zeroed) {
+        let (block, count) = self.take(class, 1);
+        if count == 0 {
+            return (null_mut(), Zeroed::No);
+        }
+        (block as *mut u8, unsafe { (*block).zeroed() })
+    }
+
+    /// Returns a null-terminated list of slots of `class`.
+    ///
+    /// # Safety
+    /// Every block in the list must be a handed out slot of `class` owned by this heap.
+    pub(crate) unsafe fn give_back(&mut self, class: usize, mut head: *mut FreeBlock) {
+        while !head.is_null() {
+            unsafe {
+                let next = (*head).next();
+                self.give_back_one(class, head as *mut u8);
+                head = next;
+            }
+        }
+    }
+
+    /// Returns a single slot of `class`.
+    ///
+    /// # Safety
+    /// ptr must be a handed out slot of `class` owned by this heap.
+    pub(crate) unsafe fn give_back_one(&mut self, class: usize, ptr: *mut u8) {
+        let slab = &mut self.classes[class];
+        let segment = Segment::of(ptr);
+        unsafe {
+            Segment::push(segment, ptr);
+            slab.mark_available(segment);
+            slab.release_if_empty(segment, self.never_unmap);
+        }
+    }
+}
+
+/// The number of slots moved between a thread cache and the slabs at once.
+pub(crate) const fn batch_size(class: usize) -> usize {
+    let slots = SEGMENT_SIZE / 16 / class_size(class);
+    if slots > 32 {
+        32
+    } else if slots == 0 {
+        1
+    } else {
+        slots
+    }
+}
+
+/// Frees a slot owned by another heap by pushing it onto the remote list of its segment.
+///
+/// # Safety
+/// ptr must be a handed out slot that is not owned by the caller.
+pub(crate) unsafe fn free_remote(ptr: *mut u8) {
+    let segment = Segment::of(ptr);
+    let block = ptr as *mut FreeBlock;
+    unsafe {
+        let mut head = (*segment).remote.load(Ordering::Relaxed);
+        loop {
+            FreeBlock::init(block, head, false);
+            match (*segment).remote.compare_exchange_weak(
+                head,
+                block,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+    }
+}
+
+/// Takes a slot of `class` from the global heap, for threads that can't use their own.
+pub(crate) fn take_global(class: usize) -> (*mut u8, Zeroed) {
+    GLOBAL.lock().take_one(class)
+}
+
+/// Releases the segments of the global heap without slots in use.
+pub(crate) fn release_empty_global() {
+    GLOBAL.lock().release_empty();
+}
+
+/// Releases the abandoned segments whose slots were all freed after their heap went away.
+/// Segments that are never unmapped give back their pages instead and stay to be adopted.
+pub(crate) fn release_abandoned() {
+    for (never_unmap, abandoned) in ABANDONED.iter().enumerate() {
+        let mut abandoned = abandoned.lock();
+        for list in abandoned.iter_mut() {
+            let mut segment = list.head;
+            while !segment.is_null() {
+                unsafe {
+                    let next = (*segment).next;
+                    Segment::drain_remote(segment);
+                    if (*segment).used == 0 {
+                        if never_unmap == 1 {
+                            Segment::purge(segment);
+                        } else {
+                            list.unlink(segment);
+                            Segment::release(segment);
+                        }
+                    }
+                    segment = next;
+                }
+            }
+        }
+    }
+}
+
+/// Frees a slot without access to the heap of the current thread.
+///
+/// # Safety
+/// ptr must be a handed out slot of `class`.
+pub(crate) unsafe fn free_global(class: usize, ptr: *mut u8) {
+    let owner = unsafe { (*Segment::of(ptr)).owner.load(Ordering::Relaxed) };
+    if owner == GLOBAL_OWNER {
+        unsafe { GLOBAL.lock().give_back_one(class, ptr) };
+    } else {
+        unsafe { free_remote(ptr) };
+    }
+}
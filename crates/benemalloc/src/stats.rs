@@ -0,0 +1,320 @@
+//! Counters of the allocator, cheap enough to always be enabled.
+//!
+//! Every thread counts into its own heap, with plain loads and stores of atomics that only it
+//! writes. [`stats`] sums the counters of all living threads under the lock of their registry.
+//! Exiting threads add their counters to the shared ones, which also count everything done
+//! without the heap of a thread. Those paths map memory anyway, so the atomic adds don't matter.
+
+use crate::size_class::{NUM_CLASSES, class_size};
+use crate::spin::SpinLock;
+use std::cell::Cell;
+use std::ptr::null;
+use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+
+/// The number of size classes in [`Stats::classes`].
+pub const SIZE_CLASSES: usize = NUM_CLASSES;
+
+// Blocks too large to be binned are counted in the slot after the classes
+const UNBINNED: usize = NUM_CLASSES;
+
+/// A thread flushes its change of the bytes in use once it exceeds this, which is how accurate
+/// the peak is per thread.
+const PEAK_GRANULARITY: isize = 64 << 10;
+
+static SHARED: Counters = Counters::new();
+static THREADS: SpinLock<ThreadList> = SpinLock::new(ThreadList { head: null() });
+// Bytes in use as of the last flush of every thread
+static CURRENT: AtomicIsize = AtomicIsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+static MAPS: AtomicUsize = AtomicUsize::new(0);
+static UNMAPS: AtomicUsize = AtomicUsize::new(0);
+static MAPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Counters of the allocator aggregated over all threads, see [`stats`].
+#[derive(Clone, Debug)]
+pub struct Stats {
+    /// Bytes in blocks that are allocated and not freed yet. Blocks have the size of their
+    /// class, so this includes what requests were rounded up by.
+    pub current_bytes: usize,
+    /// The highest `current_bytes` so far, accurate to 64 KiB per thread.
+    pub peak_bytes: usize,
+    pub allocations: usize,
+    pub frees: usize,
+    /// Allocations served from the cache of their thread.
+    pub cache_hits: usize,
+    /// Allocations that had to go to the slabs or the OS.
+    pub cache_misses: usize,
+    /// How often memory was mapped from the OS.
+    pub maps: usize,
+    /// How often memory was given back to the OS.
+    pub unmaps: usize,
+    /// Bytes currently mapped from the OS, including cached and unused memory.
+    pub mapped_bytes: usize,
+    /// The bytes in use per size class, from the smallest to the largest class.
+    pub classes: [ClassStats; SIZE_CLASSES],
+}
+
+impl Stats {
+    /// Returns the share of allocations served from the thread caches.
+    pub fn cache_hit_ratio(&self) -> f64 {
+        let total = self.cache_hits + self.cache_misses;
+        if total == 0 {
+            return 0.0;
+        }
+        self.cache_hits as f64 / total as f64
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ClassStats {
+    /// The size of every block in the class.
+    pub block_size: usize,
+    /// Bytes in blocks of this class that are allocated and not freed yet.
+    pub current_bytes: usize,
+}
+
+/// Returns the counters of the allocator, summed over all threads.
+///
+/// This is meant to be polled, e.g. for metrics. It takes a lock every thread takes once when
+/// it starts and once when it exits, so it doesn't slow down allocations.
+pub fn stats() -> Stats {
+    let sum = Counters::new();
+    sum.add(&SHARED);
+    {
+        let threads = THREADS.lock();
+        let mut thread = threads.head;
+        while !thread.is_null() {
+            // Registered threads unlink themselves under the lock before they go away
+            let stats = unsafe { &*thread };
+            sum.add(&stats.counters);
+            thread = stats.next.get();
+        }
+    }
+
+    let in_use = |slot: usize| sum.in_use[slot].load(Ordering::Relaxed);
+    let current_bytes = (0..=UNBINNED).fold(0usize, |total, slot| total.wrapping_add(in_use(slot)));
+    Stats {
+        current_bytes,
+        peak_bytes: PEAK.load(Ordering::Relaxed).max(current_bytes),
+        allocations: sum.allocations.load(Ordering::Relaxed),
+        frees: sum.frees.load(Ordering::Relaxed),
+        cache_hits: sum.hits.load(Ordering::Relaxed),
+        cache_misses: sum.misses.load(Ordering::Relaxed),
+        maps: MAPS.load(Ordering::Relaxed),
+        unmaps: UNMAPS.load(Ordering::Relaxed),
+        mapped_bytes: MAPPED.load(Ordering::Relaxed),
+        classes: std::array::from_fn(|class| ClassStats {
+            block_size: class_size(class),
+            current_bytes: in_use(class),
+        }),
+    }
+}
+
+// Either the counters of one thread, which only that thread writes, or the shared ones
+struct Counters {
+    allocations: AtomicUsize,
+    frees: AtomicUsize,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    // Bytes in use per class. The counters of a thread wrap around when it frees blocks of other
+    // threads, only the sum over all threads is meaningful.
+    in_use: [AtomicUsize; NUM_CLASSES + 1],
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Self {
+            allocations: AtomicUsize::new(0),
+            frees: AtomicUsize::new(0),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            in_use: [const { AtomicUsize::new(0) }; NUM_CLASSES + 1],
+        }
+    }
+
+    fn add(&self, other: &Counters) {
+        let add = |counter: &AtomicUsize, other: &AtomicUsize| {
+            counter.fetch_add(other.load(Ordering::Relaxed), Ordering::Relaxed);
+        };
+        add(&self.allocations, &other.allocations);
+        add(&self.frees, &other.frees);
+        add(&self.hits, &other.hits);
+        add(&self.misses, &other.misses);
+        for (counter, other) in self.in_use.iter().zip(&other.in_use) {
+            add(counter, other);
+        }
+    }
+}
+
+// Adds to a counter only the current thread writes, which needs no atomic read-modify-write
+#[inline]
+fn bump(counter: &AtomicUsize, by: usize) {
+    counter.store(
+        counter.load(Ordering::Relaxed).wrapping_add(by),
+        Ordering::Relaxed,
+    );
+}
+
+/// The counters of one thread, part of its heap.
+pub(crate) struct ThreadStats {
+    counters: Counters,
+    // Change of the bytes in use that is not added to CURRENT yet
+    pending: Cell<isize>,
+    registered: Cell<bool>,
+    // Links in the registry, only touched under its lock
+    prev: Cell<*const ThreadStats>,
+    next: Cell<*const ThreadStats>,
+}
+
+impl ThreadStats {
+    pub(crate) const fn new() -> Self {
+        Self {
+            counters: Counters::new(),
+            pending: Cell::new(0),
+            registered: Cell::new(false),
+            prev: Cell::new(null()),
+            next: Cell::new(null()),
+        }
+    }
+
+    /// Adds the counters to the registry, so [`stats`] sees them. They must not move until
+    /// [`ThreadStats::unregister`] is called.
+    #[inline]
+    pub(crate) fn register(&self) {
+        if !self.registered.get() {
+            self.register_slow();
+        }
+    }
+
+    #[cold]
+    fn register_slow(&self) {
+        let mut threads = THREADS.lock();
+        self.next.set(threads.head);
+        if !threads.head.is_null() {
+            unsafe { (*threads.head).prev.set(self) };
+        }
+        threads.head = self;
+        self.registered.set(true);
+    }
+
+    /// Moves the counters over to the shared ones, for when the thread exits.
+    pub(crate) fn unregister(&self) {
+        if !self.registered.replace(false) {
+            return;
+        }
+        let mut threads = THREADS.lock();
+        let (prev, next) = (self.prev.get(), self.next.get());
+        if prev.is_null() {
+            threads.head = next;
+        } else {
+            unsafe { (*prev).next.set(next) };
+        }
+        if !next.is_null() {
+            unsafe { (*next).prev.set(prev) };
+        }
+        SHARED.add(&self.counters);
+        flush(self.pending.replace(0));
+    }
+
+    pub(crate) fn alloc(&self, class: Option<usize>, size: usize, hit: bool) {
+        let counters = &self.counters;
+        bump(&counters.allocations, 1);
+        bump(
+            if hit {
+                &counters.hits
+            } else {
+                &counters.misses
+            },
+            1,
+        );
+        bump(&counters.in_use[class.unwrap_or(UNBINNED)], size);
+        self.change(size as isize);
+    }
+
+    pub(crate) fn free(&self, class: Option<usize>, size: usize) {
+        let counters = &self.counters;
+        bump(&counters.frees, 1);
+        bump(
+            &counters.in_use[class.unwrap_or(UNBINNED)],
+            size.wrapping_neg(),
+        );
+        self.change(-(size as isize));
+    }
+
+    #[inline]
+    fn change(&self, by: isize) {
+        let pending = self.pending.get() + by;
+        if pending.abs() < PEAK_GRANULARITY {
+            self.pending.set(pending);
+        } else {
+            self.pending.set(0);
+            flush(pending);
+        }
+    }
+}
+
+// The registered threads
+struct ThreadList {
+    head: *const ThreadStats,
+}
+
+// The counters of a thread stay where they are while it is registered
+unsafe impl Send for ThreadList {}
+
+fn flush(pending: isize) {
+    let current = CURRENT.fetch_add(pending, Ordering::Relaxed) + pending;
+    if current > 0 {
+        PEAK.fetch_max(current as usize, Ordering::Relaxed);
+    }
+}
+
+/// Counts an allocation made without the heap of the current thread.
+pub(crate) fn alloc_shared(class: Option<usize>, size: usize) {
+    SHARED.allocations.fetch_add(1, Ordering::Relaxed);
+    SHARED.misses.fetch_add(1, Ordering::Relaxed);
+    SHARED.in_use[class.unwrap_or(UNBINNED)].fetch_add(size, Ordering::Relaxed);
+    flush(size as isize);
+}
+
+/// Counts a free made without the heap of the current thread.
+pub(crate) fn free_shared(class: Option<usize>, size: usize) {
+    free_shared_many(class, 1, size);
+}
+
+/// Counts `count` blocks of `size` bytes that were freed at once, e.g. with their [`Heap`].
+///
+/// [`Heap`]: crate::Heap
+pub(crate) fn free_shared_many(class: Option<usize>, count: usize, size: usize) {
+    SHARED.frees.fetch_add(count, Ordering::Relaxed);
+    SHARED.in_use[class.unwrap_or(UNBINNED)].fetch_sub(count * size, Ordering::Relaxed);
+    flush(-((count * size) as isize));
+}
+
+/// Counts a block that was moved to another class in place.
+pub(crate) fn resize_shared(
+    class: Option<usize>,
+    size: usize,
+    new_class: Option<usize>,
+    new_size: usize,
+) {
+    SHARED.in_use[class.unwrap_or(UNBINNED)].fetch_sub(size, Ordering::Relaxed);
+    SHARED.in_use[new_class.unwrap_or(UNBINNED)].fetch_add(new_size, Ordering::Relaxed);
+    flush(new_size as isize - size as isize);
+}
+
+/// Counts memory mapped from the OS.
+pub(crate) fn mapped(size: usize) {
+    MAPS.fetch_add(1, Ordering::Relaxed);
+    MAPPED.fetch_add(size, Ordering::Relaxed);
+}
+
+/// Counts memory given back to the OS.
+pub(crate) fn unmapped(size: usize) {
+    UNMAPS.fetch_add(1, Ordering::Relaxed);
+    MAPPED.fetch_sub(size, Ordering::Relaxed);
+}
+
+/// Counts a mapping that was resized by the OS.
+pub(crate) fn remapped(size: usize, new_size: usize) {
+    MAPPED.fetch_add(new_size.wrapping_sub(size), Ordering::Relaxed);
+}
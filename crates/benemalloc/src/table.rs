@@ -0,0 +1,232 @@
+//! A hash map from the addresses of blocks to what the debugging features and heaps know about
+//! them. It lives in memory mapped from the OS, so it never allocates from the heap it keeps
+//! track of.
+
+#[cfg(any(feature = "leak_check", feature = "hardened"))]
+use crate::spin::SpinLock;
+use crate::stats;
+use allocations::{allocate, deallocate};
+use std::mem::size_of;
+use std::os::raw::c_void;
+use std::ptr::null_mut;
+
+const MIN_CAPACITY: usize = 1024;
+
+// Entries with the address zero are free. Mapped memory is zero, so a new table is empty.
+#[derive(Copy, Clone)]
+struct Entry<V> {
+    addr: usize,
+    value: V,
+}
+
+/// Open addressing with linear probing. Values have to be plain data that is valid as all zeros.
+pub(crate) struct AddrMap<V> {
+    entries: *mut Entry<V>,
+    // A power of two, or zero before the first insert
+    capacity: usize,
+    len: usize,
+}
+
+unsafe impl<V: Send> Send for AddrMap<V> {}
+
+impl<V: Copy> AddrMap<V> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            entries: null_mut(),
+            capacity: 0,
+            len: 0,
+        }
+    }
+
+    fn entries(&self) -> &[Entry<V>] {
+        if self.entries.is_null() {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(self.entries, self.capacity) }
+    }
+
+    fn entries_mut(&mut self) -> &mut [Entry<V>] {
+        if self.entries.is_null() {
+            return &mut [];
+        }
+        unsafe { std::slice::from_raw_parts_mut(self.entries, self.capacity) }
+    }
+
+    fn home(&self, addr: usize) -> usize {
+        // Blocks are at least 8 byte aligned, so the low bits carry nothing
+        let hash = (addr as u64 >> 3).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        (hash >> (64 - self.capacity.trailing_zeros())) as usize
+    }
+
+    // Keep the table at most three quarters full so probes stay short
+    fn full(&self) -> bool {
+        (self.len + 1) * 4 > self.capacity * 3
+    }
+
+    /// Inserts or replaces the value of `addr`. Returns false if the table is full and no memory
+    /// could be mapped to grow it.
+    pub(crate) fn insert(&mut self, addr: usize, value: V) -> bool {
+        if self.full() && !self.grow() {
+            return false;
+        }
+        let mask = self.capacity - 1;
+        let mut index = self.home(addr);
+        let entries = self.entries_mut();
+        while entries[index].addr != 0 && entries[index].addr != addr {
+            index = (index + 1) & mask;
+        }
+        let new = entries[index].addr == 0;
+        entries[index] = Entry { addr, value };
+        if new {
+            self.len += 1;
+        }
+        true
+    }
+
+    #[cfg_attr(not(feature = "leak_check"), allow(dead_code))]
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Like [`AddrMap::insert`], but once the table is full the entries `stale` returns true for
+    /// make room first. It only grows if more than half of it is still in use afterwards, so
+    /// every cleanup is paid for by many inserts.
+    #[cfg_attr(not(feature = "hardened"), allow(dead_code))]
+    pub(crate) fn insert_evicting(
+        &mut self,
+        addr: usize,
+        value: V,
+        mut stale: impl FnMut(&V) -> bool,
+    ) -> bool {
+        if self.full() {
+            let mut index = 0;
+            while index < self.capacity {
+                let entry = self.entries()[index];
+                // Removing shifts the next entry into this one, so it is looked at again
+                if entry.addr != 0 && stale(&entry.value) {
+                    self.remove(entry.addr);
+                } else {
+                    index += 1;
+                }
+            }
+            if self.len * 2 > self.capacity {
+                self.grow();
+            }
+        }
+        self.insert(addr, value)
+    }
+
+    #[cfg_attr(not(feature = "hardened"), allow(dead_code))]
+    pub(crate) fn get(&self, addr: usize) -> Option<V> {
+        let index = self.find(addr)?;
+        Some(self.entries()[index].value)
+    }
+
+    fn find(&self, addr: usize) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+        let mask = self.capacity - 1;
+        let entries = self.entries();
+        let mut index = self.home(addr);
+        while entries[index].addr != addr {
+            if entries[index].addr == 0 {
+                return None;
+            }
+            index = (index + 1) & mask;
+        }
+        Some(index)
+    }
+
+    pub(crate) fn remove(&mut self, addr: usize) -> Option<V> {
+        let index = self.find(addr)?;
+        let mask = self.capacity - 1;
+        let removed = self.entries()[index].value;
+        // Shift the entries after it back, so lookups don't stop at the hole
+        let mut hole = index;
+        let mut next = (hole + 1) & mask;
+        loop {
+            let addr = self.entries()[next].addr;
+            if addr == 0 {
+                break;
+            }
+            let home = self.home(addr);
+            // Entries may only move towards their home, not past it
+            if (next.wrapping_sub(home) & mask) >= (next.wrapping_sub(hole) & mask) {
+                let entries = self.entries_mut();
+                entries[hole] = entries[next];
+                hole = next;
+            }
+            next = (next + 1) & mask;
+        }
+        self.entries_mut()[hole].addr = 0;
+        self.len -= 1;
+        Some(removed)
+    }
+
+    /// Calls `f` with every address and its value.
+    pub(crate) fn for_each(&self, mut f: impl FnMut(usize, V)) {
+        for entry in self.entries() {
+            if entry.addr != 0 {
+                f(entry.addr, entry.value);
+            }
+        }
+    }
+
+    fn grow(&mut self) -> bool {
+        let capacity = (self.capacity * 2).max(MIN_CAPACITY);
+        let bytes = capacity * size_of::<Entry<V>>();
+        let entries = allocate(bytes) as *mut Entry<V>;
+        if entries.is_null() {
+            return false;
+        }
+        stats::mapped(bytes);
+        let old = std::mem::replace(
+            self,
+            Self {
+                entries,
+                capacity,
+                len: 0,
+            },
+        );
+        old.for_each(|addr, value| {
+            self.insert(addr, value);
+        });
+        true
+    }
+}
+
+impl<V> Drop for AddrMap<V> {
+    fn drop(&mut self) {
+        if !self.entries.is_null() {
+            let bytes = self.capacity * size_of::<Entry<V>>();
+            unsafe { deallocate(self.entries as *mut c_void, bytes) };
+            stats::unmapped(bytes);
+        }
+    }
+}
+
+// Blocks are spread over the shards by address, so threads rarely wait for each other
+#[cfg(any(feature = "leak_check", feature = "hardened"))]
+const SHARDS: usize = 64;
+
+/// An [`AddrMap`] split into shards with a lock each.
+#[cfg(any(feature = "leak_check", feature = "hardened"))]
+pub(crate) struct Shards<V>([SpinLock<AddrMap<V>>; SHARDS]);
+
+#[cfg(any(feature = "leak_check", feature = "hardened"))]
+impl<V: Copy> Shards<V> {
+    pub(crate) const fn new() -> Self {
+        Self([const { SpinLock::new(AddrMap::new()) }; SHARDS])
+    }
+
+    /// The shard of `addr`. Only one shard may be locked at a time.
+    pub(crate) fn shard(&self, addr: usize) -> &SpinLock<AddrMap<V>> {
+        &self.0[(addr >> 3) % SHARDS]
+    }
+
+    #[cfg_attr(not(feature = "leak_check"), allow(dead_code))]
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &SpinLock<AddrMap<V>>> {
+        self.0.iter()
+    }
+}
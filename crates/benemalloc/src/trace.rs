@@ -0,0 +1,201 @@
+//! The binary format of allocation traces, recorded with the `track_allocations` feature.
+//!
+//! A trace starts with a [`Header`] of [`HEADER_SIZE`] bytes, followed by records of
+//! [`RECORD_SIZE`] bytes each. All integers are little endian.
+//!
+//! | Offset | Type  | Field |
+//! | ------ | ----- | ----- |
+//! | 0      | `u64` | Nanoseconds since the trace started |
+//! | 8      | `u32` | Id of the thread, in the order threads first allocated |
+//! | 12     | `u8`  | [`Kind`] |
+//! | 13     | `u8`  | [`Action`] |
+//! | 14     | `u16` | Reserved, zero |
+//! | 16     | `u64` | Address of the block |
+//! | 24     | `u64` | Requested size, the new size for resizes |
+//! | 32     | `u64` | New address for resizes, zero otherwise |
+//! | 40     | `u64` | Hash of the call site, zero unless enabled with `BENEMALLOC_TRACK_CALLSITE` |
+//!
+//! Every thread buffers its records and writes them in batches, so records are only ordered by
+//! time within a thread. Sort by [`Record::timestamp_ns`] for the global order.
+//!
+//! ```no_run
+//! use benemalloc::trace::{Kind, Reader};
+//!
+//! let file = std::fs::File::open("benemalloc-1234.trace").unwrap();
+//! let mut allocated = 0;
+//! for record in Reader::new(std::io::BufReader::new(file)).unwrap() {
+//!     let record = record.unwrap();
+//!     if record.kind == Kind::Alloc {
+//!         allocated += record.size;
+//!     }
+//! }
+//! println!("{allocated} bytes allocated");
+//! ```
+
+use std::io::{self, Read};
+
+pub const MAGIC: [u8; 8] = *b"BENETRCE";
+pub const VERSION: u32 = 1;
+pub const HEADER_SIZE: usize = 16;
+pub const RECORD_SIZE: usize = 48;
+
+/// The start of every trace: [`MAGIC`], then the version and the record size as `u32`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub version: u32,
+    pub record_size: u32,
+}
+
+impl Header {
+    pub const fn current() -> Self {
+        Self {
+            version: VERSION,
+            record_size: RECORD_SIZE as u32,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0; HEADER_SIZE];
+        bytes[..8].copy_from_slice(&MAGIC);
+        bytes[8..12].copy_from_slice(&self.version.to_le_bytes());
+        bytes[12..].copy_from_slice(&self.record_size.to_le_bytes());
+        bytes
+    }
+
+    /// Returns `None` if the bytes don't start with [`MAGIC`].
+    pub fn from_bytes(bytes: &[u8; HEADER_SIZE]) -> Option<Self> {
+        if bytes[..8] != MAGIC {
+            return None;
+        }
+        Some(Self {
+            version: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            record_size: u32::from_le_bytes(bytes[12..].try_into().unwrap()),
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Kind {
+    Alloc = 1,
+    Free = 2,
+    Resize = 3,
+}
+
+/// Where an allocation came from or where a freed block went.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Action {
+    /// Not recorded, e.g. for resizes
+    None = 0,
+    /// The thread cache
+    Cache = 1,
+    /// The slabs of the thread
+    Slab = 2,
+    /// The remote list of another thread's segment
+    Remote = 3,
+    /// The OS or the global heap
+    System = 4,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub timestamp_ns: u64,
+    pub thread: u32,
+    pub kind: Kind,
+    pub action: Action,
+    pub addr: u64,
+    pub size: u64,
+    pub new_addr: u64,
+    pub callsite: u64,
+}
+
+impl Record {
+    pub fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        let mut bytes = [0; RECORD_SIZE];
+        bytes[0..8].copy_from_slice(&self.timestamp_ns.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.thread.to_le_bytes());
+        bytes[12] = self.kind as u8;
+        bytes[13] = self.action as u8;
+        bytes[16..24].copy_from_slice(&self.addr.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.size.to_le_bytes());
+        bytes[32..40].copy_from_slice(&self.new_addr.to_le_bytes());
+        bytes[40..48].copy_from_slice(&self.callsite.to_le_bytes());
+        bytes
+    }
+
+    /// Returns `None` if the kind or action is unknown.
+    pub fn from_bytes(bytes: &[u8; RECORD_SIZE]) -> Option<Self> {
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        let kind = match bytes[12] {
+            1 => Kind::Alloc,
+            2 => Kind::Free,
+            3 => Kind::Resize,
+            _ => return None,
+        };
+        let action = match bytes[13] {
+            0 => Action::None,
+            1 => Action::Cache,
+            2 => Action::Slab,
+            3 => Action::Remote,
+            4 => Action::System,
+            _ => return None,
+        };
+        Some(Self {
+            timestamp_ns: u64_at(0),
+            thread: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            kind,
+            action,
+            addr: u64_at(16),
+            size: u64_at(24),
+            new_addr: u64_at(32),
+            callsite: u64_at(40),
+        })
+    }
+}
+
+/// Decodes the records of a trace.
+pub struct Reader<R> {
+    inner: R,
+    header: Header,
+}
+
+impl<R: Read> Reader<R> {
+    /// Reads the header, fails if it is not a trace of a supported version.
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut bytes = [0; HEADER_SIZE];
+        inner.read_exact(&mut bytes)?;
+        let header = Header::from_bytes(&bytes)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a benemalloc trace"))?;
+        if header.version != VERSION || header.record_size as usize != RECORD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unsupported trace version",
+            ));
+        }
+        Ok(Self { inner, header })
+    }
+
+    pub fn header(&self) -> Header {
+        self.header
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut bytes = [0; RECORD_SIZE];
+        // A trace cut off by a crash may end in a partial record, which is dropped like the end
+        match self.inner.read_exact(&mut bytes) {
+            Ok(()) => {}
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return None,
+            Err(error) => return Some(Err(error)),
+        }
+        Some(
+            Record::from_bytes(&bytes)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "corrupt trace record")),
+        )
+    }
+}
@@ -1,18 +1,17 @@
-// TODO: Allow for tracking Allocation and Deallocation of memory
+//! Records allocation events in the binary format of [`crate::trace`].
+//!
+//! Every thread collects its records in a buffer and writes them to the trace in batches, so
+//! tracking doesn't cost a syscall per allocation.
 
-use serde::{Deserialize, Serialize};
-use std::alloc::Layout;
-use std::alloc::System;
-use std::io::Cursor;
-use std::sync::atomic::{AtomicU64, Ordering};
+use crate::trace::{Kind, RECORD_SIZE, Record};
+use crate::{backtrace, env};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Instant;
 
-#[derive(Clone, Copy, Serialize)]
-pub enum Action {
-    Cache,
-    System,
-}
+pub use crate::trace::Action;
 
-#[derive(Clone, Copy, Serialize)]
+#[derive(Clone, Copy)]
 pub enum Event {
     Alloc {
         addr: usize,
@@ -26,43 +25,108 @@ pub enum Event {
     },
     Resize {
         addr: usize,
+        new_addr: usize,
         new_size: usize,
     },
 }
 
+// The number of records buffered per thread
+const BUFFERED: usize = 128;
+// The most frames hashed into the call site of a record
+const MAX_FRAMES: usize = 32;
+
+// Thread ids start at 1, records of threads without a tracker have 0
+static NEXT_THREAD: AtomicU32 = AtomicU32::new(1);
+static START: OnceLock<Instant> = OnceLock::new();
+
 pub struct Tracker {
-    //FIXME: We cannot allocate, so maybe use atomic integers?
-    allocations: AtomicU64,
-    allocated_size: AtomicU64,
-    system_alloc: System,
+    thread: u32,
+    len: usize,
+    buf: [u8; RECORD_SIZE * BUFFERED],
 }
 
 impl Tracker {
     pub const fn new() -> Self {
         Self {
-            allocations: AtomicU64::new(0),
-            allocated_size: AtomicU64::new(0),
-            system_alloc: System,
+            thread: 0,
+            len: 0,
+            buf: [0; RECORD_SIZE * BUFFERED],
         }
     }
 
     pub fn track(&mut self, event: Event) {
-        let mut buf = [0u8; 2048];
-        let mut cursor = Cursor::new(&mut buf[..]);
-        serde_json::to_writer(&mut cursor, &event).unwrap();
-        let end = cursor.position() as usize;
-        let section = &buf[..end];
-        self.write(&buf[..end]);
-        self.write(b"\n");
-    }
-    fn write(&self, s: &[u8]) {
-        unsafe {
-            libc::write(libc::STDERR_FILENO, s.as_ptr() as _, s.len() as _);
+        if self.thread == 0 {
+            self.thread = NEXT_THREAD.fetch_add(1, Ordering::Relaxed);
+        }
+        let offset = self.len * RECORD_SIZE;
+        self.buf[offset..offset + RECORD_SIZE]
+            .copy_from_slice(&record(event, self.thread).to_bytes());
+        self.len += 1;
+        if self.len == BUFFERED {
+            self.flush();
         }
     }
 
-    pub fn print(&self) {
-        println!("Allocations: {:?}", self.allocations);
-        println!("Allocated Size: {:?}", self.allocated_size);
+    /// Writes the buffered records to the trace.
+    pub fn flush(&mut self) {
+        write(&self.buf[..self.len * RECORD_SIZE]);
+        self.len = 0;
+    }
+}
+
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Writes the record of an event right away, for threads whose buffer is already gone.
+pub fn track_unbuffered(event: Event) {
+    write(&record(event, 0).to_bytes());
+}
+
+fn record(event: Event, thread: u32) -> Record {
+    let timestamp_ns = START.get_or_init(Instant::now).elapsed().as_nanos() as u64;
+    let depth = env::options().track_callsite.min(MAX_FRAMES);
+    let callsite = if depth == 0 {
+        0
+    } else {
+        let mut frames = [0; MAX_FRAMES];
+        let len = backtrace::capture(&mut frames[..depth]);
+        backtrace::hash(&frames[..len])
+    };
+    let (kind, action, addr, size, new_addr) = match event {
+        Event::Alloc { addr, size, source } => (Kind::Alloc, source, addr, size, 0),
+        Event::Free { addr, size, action } => (Kind::Free, action, addr, size, 0),
+        Event::Resize {
+            addr,
+            new_addr,
+            new_size,
+        } => (Kind::Resize, Action::None, addr, new_size, new_addr),
+    };
+    Record {
+        timestamp_ns,
+        thread,
+        kind,
+        action,
+        addr: addr as u64,
+        size: size as u64,
+        new_addr: new_addr as u64,
+        callsite,
+    }
+}
+
+fn write(mut bytes: &[u8]) {
+    let fd = env::options().track_fd;
+    // The trace couldn't be opened
+    if fd < 0 {
+        return;
+    }
+    while !bytes.is_empty() {
+        let written = unsafe { libc::write(fd, bytes.as_ptr() as _, bytes.len() as _) };
+        if written <= 0 {
+            return;
+        }
+        bytes = &bytes[written as usize..];
     }
 }
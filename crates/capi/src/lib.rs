@@ -0,0 +1,347 @@
+//! benemalloc behind the C allocation functions, so C code linked into a program allocates from
+//! the same heaps as its Rust code, and unmodified programs can run on it:
+//!
+//! ```bash
+//! cargo build --release -p benemalloc-capi
+//! LD_PRELOAD=target/release/libbenemalloc_capi.so ./program
+//! ```
+//!
+//! Besides `malloc`, `free`, `calloc` and `realloc` this exports every function glibc expects a
+//! replacement to provide, so pointers never go to a glibc function that doesn't know them.
+//!
+//! C frees without a size, so every block starts with a header holding the layout it was
+//! allocated with. The header takes 16 bytes in front of the pointer, which keeps the alignment of
+//! `malloc` at 16 bytes. Blocks with a larger alignment put it an alignment in front instead.
+
+use allocations::page_size;
+use benemalloc::BeneAlloc;
+use libc::{c_int, c_void, size_t, EINVAL, ENOMEM};
+use std::alloc::{GlobalAlloc, Layout};
+use std::mem::size_of;
+use std::ptr::null_mut;
+
+// Rust code in here allocates from the same heaps as C code
+#[global_allocator]
+static ALLOCATOR: BeneAlloc = BeneAlloc::new();
+
+// The alignment malloc guarantees, that of max_align_t
+const MIN_ALIGN: usize = 16;
+
+// Sits right in front of every block handed out
+#[repr(C)]
+struct Header {
+    // The layout the block was allocated with, including the header
+    size: usize,
+    align: usize,
+}
+
+const _: () = assert!(size_of::<Header>() <= MIN_ALIGN);
+
+/// Returns the layout of a block with `size` usable bytes at `align`, and the offset of the
+/// usable bytes in it.
+fn layout(size: usize, align: usize) -> Option<(Layout, usize)> {
+    let align = align.max(MIN_ALIGN);
+    let layout = Layout::from_size_align(size.checked_add(align)?, align).ok()?;
+    Some((layout, align))
+}
+
+/// # Safety
+/// ptr must be a block handed out by this allocator.
+unsafe fn header(ptr: *mut c_void) -> *mut Header {
+    unsafe { (ptr as *mut Header).sub(1) }
+}
+
+/// Returns the start and the layout of the block behind `ptr`.
+///
+/// # Safety
+/// ptr must be a block handed out by this allocator.
+unsafe fn block(ptr: *mut c_void) -> (*mut u8, Layout) {
+    unsafe {
+        let header = header(ptr).read();
+        let layout = Layout::from_size_align_unchecked(header.size, header.align);
+        ((ptr as *mut u8).sub(header.align), layout)
+    }
+}
+
+/// Writes the header of a block that was just allocated and returns the pointer to hand out.
+///
+/// # Safety
+/// block must be null or allocated with `layout`, which is `offset` larger than what is asked.
+unsafe fn hand_out(block: *mut u8, layout: Layout, offset: usize) -> *mut c_void {
+    if block.is_null() {
+        set_errno(ENOMEM);
+        return null_mut();
+    }
+    unsafe {
+        let ptr = block.add(offset) as *mut c_void;
+        header(ptr).write(Header {
+            size: layout.size(),
+            align: layout.align(),
+        });
+        ptr
+    }
+}
+
+fn alloc(size: usize, align: usize) -> *mut c_void {
+    let Some((layout, offset)) = layout(size, align) else {
+        set_errno(ENOMEM);
+        return null_mut();
+    };
+    unsafe { hand_out(ALLOCATOR.alloc(layout), layout, offset) }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_errno(errno: c_int) {
+    unsafe { *libc::__errno_location() = errno };
+}
+
+#[cfg(target_os = "macos")]
+fn set_errno(errno: c_int) {
+    unsafe { *libc::__error() = errno };
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+fn set_errno(_errno: c_int) {}
+
+#[no_mangle]
+pub extern "C" fn malloc(size: size_t) -> *mut c_void {
+    alloc(size, MIN_ALIGN)
+}
+
+/// # Safety
+/// ptr must be null or a block handed out by this allocator that was not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn free(ptr: *mut c_void) {
+    if ptr.is_null() {
+        return;
+    }
+    unsafe {
+        let (block, layout) = block(ptr);
+        ALLOCATOR.dealloc(block, layout);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn calloc(count: size_t, size: size_t) -> *mut c_void {
+    let Some((layout, offset)) = count
+        .checked_mul(size)
+        .and_then(|size| layout(size, MIN_ALIGN))
+    else {
+        set_errno(ENOMEM);
+        return null_mut();
+    };
+    unsafe { hand_out(ALLOCATOR.alloc_zeroed(layout), layout, offset) }
+}
+
+/// Frees the block and returns null if `size` is zero, like glibc does.
+///
+/// # Safety
+/// ptr must be null or a block handed out by this allocator that was not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn realloc(ptr: *mut c_void, size: size_t) -> *mut c_void {
+    if ptr.is_null() {
+        return malloc(size);
+    }
+    if size == 0 {
+        unsafe { free(ptr) };
+        return null_mut();
+    }
+    unsafe {
+        let (block, old) = block(ptr);
+        let Some((layout, offset)) = layout(size, old.align()) else {
+            set_errno(ENOMEM);
+            return null_mut();
+        };
+        // The header moves along with the contents, only its size changes
+        hand_out(ALLOCATOR.realloc(block, old, layout.size()), layout, offset)
+    }
+}
+
+/// # Safety
+/// ptr must be null or a block handed out by this allocator that was not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn reallocarray(
+    ptr: *mut c_void,
+    count: size_t,
+    size: size_t,
+) -> *mut c_void {
+    match count.checked_mul(size) {
+        Some(size) => unsafe { realloc(ptr, size) },
+        None => {
+            set_errno(ENOMEM);
+            null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn aligned_alloc(align: size_t, size: size_t) -> *mut c_void {
+    if !align.is_power_of_two() {
+        set_errno(EINVAL);
+        return null_mut();
+    }
+    alloc(size, align)
+}
+
+/// # Safety
+/// memptr must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn posix_memalign(
+    memptr: *mut *mut c_void,
+    align: size_t,
+    size: size_t,
+) -> c_int {
+    if !align.is_power_of_two() || !align.is_multiple_of(size_of::<*mut c_void>()) {
+        return EINVAL;
+    }
+    let Some((layout, offset)) = layout(size, align) else {
+        return ENOMEM;
+    };
+    let block = unsafe { ALLOCATOR.alloc(layout) };
+    if block.is_null() {
+        return ENOMEM;
+    }
+    unsafe { memptr.write(hand_out(block, layout, offset)) };
+    0
+}
+
+/// Alignments that are no power of two are rounded up to one, like glibc does.
+#[no_mangle]
+pub extern "C" fn memalign(align: size_t, size: size_t) -> *mut c_void {
+    match align.checked_next_power_of_two() {
+        Some(align) => alloc(size, align),
+        None => {
+            set_errno(EINVAL);
+            null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn valloc(size: size_t) -> *mut c_void {
+    alloc(size, page_size())
+}
+
+/// Like [`valloc`], with the size rounded up to whole pages.
+#[no_mangle]
+pub extern "C" fn pvalloc(size: size_t) -> *mut c_void {
+    let page = page_size();
+    match size.checked_next_multiple_of(page) {
+        Some(size) => alloc(size.max(page), page),
+        None => {
+            set_errno(ENOMEM);
+            null_mut()
+        }
+    }
+}
+
+/// Returns how many bytes of the block may be used, which includes the rest of its size class.
+///
+/// # Safety
+/// ptr must be null or a block handed out by this allocator that was not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn malloc_usable_size(ptr: *mut c_void) -> size_t {
+    if ptr.is_null() {
+        return 0;
+    }
+    let (_, layout) = unsafe { block(ptr) };
+    BeneAlloc::usable_size(layout) - layout.align()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_sits_in_front_of_the_block() {
+        let ptr = malloc(100);
+        assert_eq!(ptr as usize % MIN_ALIGN, 0);
+        unsafe {
+            let header = header(ptr).read();
+            assert_eq!(header.size, 100 + MIN_ALIGN);
+            assert_eq!(header.align, MIN_ALIGN);
+            free(ptr);
+        }
+    }
+
+    #[test]
+    fn aligned_blocks() {
+        for align in [16, 64, 4096, 1 << 16] {
+            let ptr = aligned_alloc(align, 100);
+            assert_eq!(ptr as usize % align, 0);
+            unsafe {
+                ptr.write_bytes(1, 100);
+                assert_eq!(header(ptr).read().align, align);
+                free(ptr);
+            }
+
+            let mut ptr = null_mut();
+            assert_eq!(unsafe { posix_memalign(&mut ptr, align, 100) }, 0);
+            assert_eq!(ptr as usize % align, 0);
+            unsafe { free(ptr) };
+        }
+        assert!(aligned_alloc(24, 100).is_null());
+        let mut ptr = null_mut();
+        assert_eq!(unsafe { posix_memalign(&mut ptr, 4, 100) }, EINVAL);
+    }
+
+    #[test]
+    fn page_aligned_blocks() {
+        let page = page_size();
+        let ptr = valloc(100);
+        assert_eq!(ptr as usize % page, 0);
+        unsafe { free(ptr) };
+        let ptr = pvalloc(1);
+        assert_eq!(ptr as usize % page, 0);
+        assert!(unsafe { malloc_usable_size(ptr) } >= page);
+        unsafe { free(ptr) };
+    }
+
+    #[test]
+    fn realloc_keeps_data() {
+        unsafe {
+            let ptr = malloc(10) as *mut u8;
+            for i in 0..10 {
+                ptr.add(i).write(i as u8);
+            }
+            let grown = realloc(ptr as *mut c_void, 1 << 20) as *mut u8;
+            assert!((0..10).all(|i| *grown.add(i) == i as u8));
+            let shrunk = realloc(grown as *mut c_void, 5) as *mut u8;
+            assert!((0..5).all(|i| *shrunk.add(i) == i as u8));
+            assert!(realloc(shrunk as *mut c_void, 0).is_null());
+
+            // Aligned blocks stay aligned when they move
+            let aligned = aligned_alloc(256, 8);
+            let moved = realloc(aligned, 1 << 20);
+            assert_eq!(moved as usize % 256, 0);
+            free(moved);
+        }
+    }
+
+    #[test]
+    fn calloc_zeroes_and_checks_overflow() {
+        let ptr = calloc(10, 100) as *mut u8;
+        assert!((0..1000).all(|i| unsafe { *ptr.add(i) } == 0));
+        unsafe { free(ptr as *mut c_void) };
+        assert!(calloc(usize::MAX / 2, 3).is_null());
+        assert!(calloc(1, usize::MAX).is_null());
+        assert!(unsafe { reallocarray(null_mut(), usize::MAX / 2, 3) }.is_null());
+    }
+
+    #[test]
+    fn usable_size_covers_the_size_class() {
+        unsafe {
+            assert_eq!(malloc_usable_size(null_mut()), 0);
+            let ptr = malloc(100);
+            let usable = malloc_usable_size(ptr);
+            assert!(usable >= 100);
+            // All of it may be written without touching the next block
+            ptr.write_bytes(1, usable);
+            free(ptr);
+
+            let ptr = aligned_alloc(4096, 5000);
+            assert!(malloc_usable_size(ptr) >= 5000);
+            free(ptr);
+        }
+    }
+}
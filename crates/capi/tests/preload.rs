@@ -0,0 +1,102 @@
+//! Runs small C programs with the shared library preloaded.
+
+use benemalloc::trace::{Kind, Reader};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// Names what is built for `features`, so builds with different features don't overwrite
+// each other
+fn build_name(features: &[&str]) -> String {
+    features
+        .iter()
+        .fold("capi".to_string(), |name, feature| name + "-" + feature)
+}
+
+/// Builds the shared library with the `features` of benemalloc, in a target directory of its own.
+/// The test binaries don't link it, it would replace their malloc as well.
+fn library(features: &[&str]) -> PathBuf {
+    let target = Path::new(env!("CARGO_TARGET_TMPDIR")).join(build_name(features));
+    let mut cargo = Command::new(env!("CARGO"));
+    cargo
+        .args(["build", "--quiet", "-p", "benemalloc-capi", "--target-dir"])
+        .arg(&target);
+    for feature in features {
+        cargo.arg("--features").arg(format!("benemalloc/{feature}"));
+    }
+    assert!(cargo.status().unwrap().success());
+    target.join("debug").join(format!(
+        "{}benemalloc_capi{}",
+        std::env::consts::DLL_PREFIX,
+        std::env::consts::DLL_SUFFIX
+    ))
+}
+
+/// Compiles `source` from this directory and runs it with benemalloc preloaded, returning what
+/// it printed.
+fn run_preloaded(source: &str, features: &[&str], envs: &[(&str, &Path)]) -> String {
+    let library = library(features);
+    let source = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join(source);
+    let program = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!(
+        "{}-{}",
+        source.file_stem().unwrap().to_str().unwrap(),
+        build_name(features)
+    ));
+    let compiled = Command::new("cc")
+        .args(["-pthread", "-o"])
+        .arg(&program)
+        .arg(&source)
+        .status()
+        .expect("a C compiler is needed to run this test");
+    assert!(compiled.success());
+    let output = Command::new(&program)
+        .env("LD_PRELOAD", library)
+        .envs(envs.iter().copied())
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}: {:?}\n{}",
+        source.display(),
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn runs_a_c_program() {
+    assert_eq!(run_preloaded("smoke.c", &[], &[]), "ok\n");
+}
+
+// Registering the destructor of a new thread's heap makes glibc call calloc, which comes back
+// into benemalloc before the heap is set up
+#[cfg(target_os = "linux")]
+#[test]
+fn threads_start_their_heaps() {
+    assert_eq!(run_preloaded("threads.c", &[], &[]), "ok\n");
+}
+
+// The tracker of every thread registers a destructor of its own, which allocates as well
+#[cfg(target_os = "linux")]
+#[test]
+fn threads_start_their_trackers() {
+    let trace = Path::new(env!("CARGO_TARGET_TMPDIR")).join("threads.trace");
+    let output = run_preloaded(
+        "threads.c",
+        &["track_allocations"],
+        &[("BENEMALLOC_TRACK", &trace)],
+    );
+    assert_eq!(output, "ok\n");
+    let allocs = Reader::new(BufReader::new(File::open(&trace).unwrap()))
+        .unwrap()
+        .map(Result::unwrap)
+        .filter(|record| record.kind == Kind::Alloc)
+        .count();
+    // Every thread allocates 1000 blocks
+    assert!(allocs >= 8000, "{allocs} allocations traced");
+}
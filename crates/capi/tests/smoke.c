@@ -0,0 +1,37 @@
+// Calls the allocation functions a C program uses, from the main thread
+#define _GNU_SOURCE
+#include <malloc.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+int main(void) {
+    char *text = strdup("benemalloc");
+    text = realloc(text, 1 << 16);
+    if (strcmp(text, "benemalloc") != 0) {
+        return 1;
+    }
+    free(text);
+
+    int *numbers = calloc(1000, sizeof(int));
+    for (int i = 0; i < 1000; i++) {
+        if (numbers[i] != 0) {
+            return 2;
+        }
+    }
+    if (malloc_usable_size(numbers) < 1000 * sizeof(int)) {
+        return 3;
+    }
+    free(numbers);
+
+    void *aligned;
+    if (posix_memalign(&aligned, 4096, 100) != 0 || (uintptr_t)aligned % 4096 != 0) {
+        return 4;
+    }
+    free(aligned);
+
+    // stdio allocates its buffers
+    printf("ok\n");
+    return 0;
+}
@@ -0,0 +1,26 @@
+// Allocates in a few threads, each of which sets up a heap of its own
+#include <pthread.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+static void *run(void *arg) {
+    for (int i = 0; i < 1000; i++) {
+        char *block = malloc(i + 1);
+        memset(block, i, i + 1);
+        free(block);
+    }
+    return arg;
+}
+
+int main(void) {
+    pthread_t threads[8];
+    for (int i = 0; i < 8; i++) {
+        pthread_create(&threads[i], NULL, run, NULL);
+    }
+    for (int i = 0; i < 8; i++) {
+        pthread_join(threads[i], NULL);
+    }
+    puts("ok");
+    return 0;
+}
@@ -0,0 +1,393 @@
+//! Replays an allocation trace recorded with the `track_allocations` feature of benemalloc
+//! against several allocators, and compares their time, peak RSS and fragmentation.
+//!
+//! Run with: cargo run --release -p benemalloc-replay -- <trace> [benemalloc|system|mimalloc]...
+//!
+//! Every allocator replays in a process of its own, so they don't share memory or state. The
+//! trace is turned into a list of operations before the replay starts, which is timed alone.
+//! Events of all threads are replayed by one thread, in the order of their timestamps.
+
+use benemalloc::trace::{Kind, Reader, Record};
+use benemalloc::BeneAlloc;
+use mimalloc::MiMalloc;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::process::{exit, Command};
+use std::time::{Duration, Instant};
+
+const ALLOCATORS: &[&str] = &["benemalloc", "system", "mimalloc"];
+
+static BENE_ALLOC: BeneAlloc = BeneAlloc::new();
+
+// Traces don't record alignments, so every block gets the alignment of malloc
+const ALIGN: usize = 16;
+const PAGE_SIZE: usize = 4096;
+
+enum Op {
+    Alloc { slot: usize, size: usize },
+    Free { slot: usize },
+    Realloc { slot: usize, size: usize },
+}
+
+struct Ops {
+    ops: Vec<Op>,
+    slots: usize,
+}
+
+/// What the replay of one allocator measured.
+struct Outcome {
+    time: Duration,
+    peak_rss: usize,
+    baseline_rss: usize,
+    peak_live: usize,
+}
+
+impl Outcome {
+    /// The share of the memory the replay took from the OS that wasn't in use at the peak.
+    fn fragmentation(&self) -> f64 {
+        let used = self.peak_rss.saturating_sub(self.baseline_rss);
+        if used == 0 {
+            return 0.0;
+        }
+        (1.0 - self.peak_live as f64 / used as f64).max(0.0)
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [flag, allocator, trace] if flag == "--child" => child(allocator, trace),
+        [trace, allocators @ ..] if !trace.starts_with('-') => {
+            let allocators: Vec<&str> = if allocators.is_empty() {
+                ALLOCATORS.to_vec()
+            } else {
+                allocators.iter().map(String::as_str).collect()
+            };
+            compare(trace, &allocators);
+        }
+        _ => usage(),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: benemalloc-replay <trace> [{}]...",
+        ALLOCATORS.join("|")
+    );
+    exit(2)
+}
+
+fn compare(trace: &str, allocators: &[&str]) {
+    if let Some(unknown) = allocators.iter().find(|name| !ALLOCATORS.contains(name)) {
+        eprintln!("unknown allocator {unknown}");
+        usage();
+    }
+    let exe = std::env::current_exe().expect("Cannot find the replay binary");
+    println!(
+        "{:12} | {:>12} | {:>12} | {:>12} | {:>13}",
+        "allocator", "time", "peak RSS", "peak live", "fragmentation"
+    );
+    for allocator in allocators {
+        let output = Command::new(&exe)
+            .args(["--child", allocator, trace])
+            .output()
+            .expect("Cannot start the replay");
+        if !output.status.success() {
+            eprintln!(
+                "{allocator} failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            continue;
+        }
+        let outcome = parse_outcome(&String::from_utf8_lossy(&output.stdout));
+        println!(
+            "{:12} | {:>12.2?} | {:>12} | {:>12} | {:>12.1}%",
+            allocator,
+            outcome.time,
+            format_bytes(outcome.peak_rss - outcome.baseline_rss),
+            format_bytes(outcome.peak_live),
+            outcome.fragmentation() * 100.0
+        );
+    }
+}
+
+// Replays in this process and prints the outcome for the parent
+fn child(allocator: &str, trace: &str) {
+    let ops = load(trace);
+    let outcome = match allocator {
+        "benemalloc" => replay(&BENE_ALLOC, &ops),
+        "system" => replay(&System, &ops),
+        "mimalloc" => replay(&MiMalloc, &ops),
+        _ => usage(),
+    };
+    println!(
+        "{} {} {} {}",
+        outcome.time.as_nanos(),
+        outcome.peak_rss,
+        outcome.baseline_rss,
+        outcome.peak_live
+    );
+}
+
+fn parse_outcome(line: &str) -> Outcome {
+    let fields: Vec<u128> = line
+        .split_whitespace()
+        .map(|field| field.parse().expect("Malformed output of the replay"))
+        .collect();
+    Outcome {
+        time: Duration::from_nanos(fields[0] as u64),
+        peak_rss: fields[1] as usize,
+        baseline_rss: fields[2] as usize,
+        peak_live: fields[3] as usize,
+    }
+}
+
+/// Turns the records of a trace into operations on slots, so the replay needs no lookups.
+fn load(path: &str) -> Ops {
+    let file = File::open(path).unwrap_or_else(|error| {
+        eprintln!("cannot open {path}: {error}");
+        exit(1)
+    });
+    let mut records = Reader::new(BufReader::new(file))
+        .and_then(|reader| reader.collect::<std::io::Result<Vec<Record>>>())
+        .unwrap_or_else(|error| {
+            eprintln!("cannot read {path}: {error}");
+            exit(1)
+        });
+    // Threads write their records in batches, so only the records of one thread are in order
+    records.sort_by_key(|record| record.timestamp_ns);
+
+    let mut live: HashMap<u64, usize> = HashMap::new();
+    let mut ops = Vec::with_capacity(records.len());
+    let mut slots = 0;
+    for record in records {
+        match record.kind {
+            Kind::Alloc if record.addr != 0 => {
+                live.insert(record.addr, slots);
+                ops.push(Op::Alloc {
+                    slot: slots,
+                    size: record.size as usize,
+                });
+                slots += 1;
+            }
+            // Blocks that were allocated before the trace started are skipped
+            Kind::Free => {
+                if let Some(slot) = live.remove(&record.addr) {
+                    ops.push(Op::Free { slot });
+                }
+            }
+            Kind::Resize => {
+                if let Some(slot) = live.remove(&record.addr) {
+                    live.insert(record.new_addr, slot);
+                    ops.push(Op::Realloc {
+                        slot,
+                        size: record.size as usize,
+                    });
+                }
+            }
+            // Failed allocations
+            Kind::Alloc => {}
+        }
+    }
+    Ops { ops, slots }
+}
+
+fn layout(size: usize) -> Layout {
+    Layout::from_size_align(size.max(1), ALIGN).expect("Invalid layout")
+}
+
+fn replay<A: GlobalAlloc>(allocator: &A, ops: &Ops) -> Outcome {
+    let mut blocks: Vec<(*mut u8, usize)> = vec![(std::ptr::null_mut(), 0); ops.slots];
+    reset_peak_rss();
+    let baseline_rss = rss();
+    let (mut live, mut peak_live) = (0usize, 0usize);
+
+    let start = Instant::now();
+    for op in &ops.ops {
+        unsafe {
+            match *op {
+                Op::Alloc { slot, size } => {
+                    let ptr = allocator.alloc(layout(size));
+                    if ptr.is_null() {
+                        eprintln!("out of memory");
+                        exit(1);
+                    }
+                    touch(ptr, size);
+                    blocks[slot] = (ptr, size);
+                    live += size;
+                }
+                Op::Free { slot } => {
+                    let (ptr, size) = blocks[slot];
+                    allocator.dealloc(ptr, layout(size));
+                    live -= size;
+                }
+                Op::Realloc { slot, size } => {
+                    let (ptr, old_size) = blocks[slot];
+                    let ptr = allocator.realloc(ptr, layout(old_size), size.max(1));
+                    if ptr.is_null() {
+                        eprintln!("out of memory");
+                        exit(1);
+                    }
+                    if size > old_size {
+                        touch(ptr.add(old_size), size - old_size);
+                    }
+                    blocks[slot] = (ptr, size);
+                    live = live - old_size + size;
+                }
+            }
+        }
+        peak_live = peak_live.max(live);
+    }
+    let time = start.elapsed();
+
+    Outcome {
+        time,
+        peak_rss: peak_rss().max(baseline_rss),
+        baseline_rss,
+        peak_live,
+    }
+}
+
+// Writes to every page of a block like its owner would, so all of it counts towards the RSS
+unsafe fn touch(ptr: *mut u8, size: usize) {
+    for offset in (0..size).step_by(PAGE_SIZE) {
+        unsafe { ptr.add(offset).write(0xbe) };
+    }
+}
+
+// Reads a field of /proc/self/status in bytes
+#[cfg(target_os = "linux")]
+fn proc_status(field: &str) -> usize {
+    let status = std::fs::read_to_string("/proc/self/status").unwrap_or_default();
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix(field))
+        .and_then(|value| value.trim_end_matches("kB").trim().parse::<usize>().ok())
+        .map_or(0, |kb| kb << 10)
+}
+
+#[cfg(target_os = "linux")]
+fn rss() -> usize {
+    proc_status("VmRSS:")
+}
+
+#[cfg(target_os = "linux")]
+fn peak_rss() -> usize {
+    proc_status("VmHWM:")
+}
+
+// Loading the trace may have taken more memory than the replay will
+#[cfg(target_os = "linux")]
+fn reset_peak_rss() {
+    let _ = std::fs::write("/proc/self/clear_refs", "5");
+}
+
+// Elsewhere only the peak of the whole process is known, which includes loading the trace
+#[cfg(not(target_os = "linux"))]
+fn rss() -> usize {
+    0
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peak_rss() -> usize {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+    // Bytes on macOS
+    usage.ru_maxrss as usize
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reset_peak_rss() {}
+
+fn format_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use benemalloc::trace::{Action, Header};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Counts the calls that reach System
+    #[derive(Default)]
+    struct Counting {
+        allocs: AtomicUsize,
+        frees: AtomicUsize,
+        reallocs: AtomicUsize,
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            self.allocs.fetch_add(1, Ordering::Relaxed);
+            unsafe { System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            self.frees.fetch_add(1, Ordering::Relaxed);
+            unsafe { System.dealloc(ptr, layout) }
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            self.reallocs.fetch_add(1, Ordering::Relaxed);
+            unsafe { System.realloc(ptr, layout, new_size) }
+        }
+    }
+
+    fn record(timestamp_ns: u64, kind: Kind, addr: u64, size: u64, new_addr: u64) -> Record {
+        Record {
+            timestamp_ns,
+            thread: 0,
+            kind,
+            action: Action::None,
+            addr,
+            size,
+            new_addr,
+            callsite: 0,
+        }
+    }
+
+    #[test]
+    fn replays_a_trace() {
+        // Written out of order, like the batches of several threads
+        let records = [
+            record(4, Kind::Free, 0x2000, 100, 0),
+            record(1, Kind::Alloc, 0x1000, 24, 0),
+            record(2, Kind::Alloc, 0x2000, 100, 0),
+            record(3, Kind::Resize, 0x1000, 5000, 0x3000),
+            // Allocated before the trace started
+            record(5, Kind::Free, 0x9000, 64, 0),
+            record(6, Kind::Free, 0x3000, 5000, 0),
+            // Failed
+            record(7, Kind::Alloc, 0, 10, 0),
+            record(8, Kind::Alloc, 0x4000, 8, 0),
+        ];
+        let mut trace = Header::current().to_bytes().to_vec();
+        for record in &records {
+            trace.extend_from_slice(&record.to_bytes());
+        }
+        let path = std::env::temp_dir().join(format!("replay-{}.trace", std::process::id()));
+        std::fs::write(&path, trace).unwrap();
+        let ops = load(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(ops.slots, 3);
+        assert_eq!(ops.ops.len(), 6);
+
+        let counting = Counting::default();
+        let outcome = replay(&counting, &ops);
+        assert_eq!(counting.allocs.load(Ordering::Relaxed), 3);
+        assert_eq!(counting.frees.load(Ordering::Relaxed), 2);
+        assert_eq!(counting.reallocs.load(Ordering::Relaxed), 1);
+        // Both blocks were live after the first one grew
+        assert_eq!(outcome.peak_live, 5100);
+    }
+}
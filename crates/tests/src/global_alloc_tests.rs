@@ -2,7 +2,13 @@ use crate::ALLOCATOR;
 use benemalloc::BeneAlloc;
 use rand::RngCore;
 use rand::{thread_rng, Rng};
-use std::{collections::BinaryHeap, hint::black_box, thread, thread::available_parallelism};
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    collections::{BinaryHeap, HashSet},
+    hint::black_box,
+    thread,
+    thread::available_parallelism,
+};
 use tracing::{info, info_span};
 
 #[test]
@@ -108,6 +114,305 @@ fn test_box_allocation() {
     }
 }
 
+#[test]
+fn test_small_objects_share_pages() {
+    let boxes: Vec<Box<u64>> = (0..10_000).map(Box::new).collect();
+    // Small objects are carved from shared segments instead of getting a page each
+    let pages: HashSet<usize> = boxes
+        .iter()
+        .map(|value| &**value as *const u64 as usize / 4096)
+        .collect();
+    assert!(pages.len() < boxes.len() / 100);
+    for (i, value) in boxes.iter().enumerate() {
+        assert_eq!(**value, i as u64);
+    }
+}
+
+#[test]
+fn test_zeroed_after_reuse() {
+    for size in [8, 100, 3000, 100_000] {
+        let mut dirty = vec![0xffu8; size];
+        dirty[0] = 0xfe;
+        drop(dirty);
+        // The freed block is recycled here and has to be cleared again
+        let zeroed = vec![0u8; size];
+        assert!(zeroed.iter().all(|&byte| byte == 0));
+    }
+}
+
+#[test]
+fn test_large_alignments() {
+    for align in [8192, 64 << 10, 2 << 20] {
+        for size in [16, 4096, 100_000] {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            unsafe {
+                let ptr = ALLOCATOR.alloc(layout);
+                assert_eq!(ptr as usize % align, 0);
+                ptr.write_bytes(1, size);
+                ALLOCATOR.dealloc(ptr, layout);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_remote_frees_return_to_owner() {
+    let mut addresses = HashSet::new();
+    for _ in 0..20 {
+        let batch: Vec<Box<[u64; 4]>> = (0..10_000).map(|i| Box::new([i; 4])).collect();
+        addresses.extend(batch.iter().map(|value| &**value as *const _ as usize));
+        // Freed on another thread, the slots still go back to this one
+        thread::spawn(move || drop(batch)).join().unwrap();
+    }
+    assert!(addresses.len() < 3 * 10_000);
+}
+
+#[test]
+fn test_exited_threads_leave_memory_behind() {
+    let mut addresses = HashSet::new();
+    for _ in 0..20 {
+        // The segments of the exited thread are adopted by the next one
+        let batch = thread::spawn(|| (0..10_000).map(|i| Box::new([i; 4])).collect::<Vec<_>>())
+            .join()
+            .unwrap();
+        addresses.extend(batch.iter().map(|value| &**value as *const _ as usize));
+    }
+    assert!(addresses.len() < 3 * 10_000);
+}
+
+#[test]
+fn test_exited_threads_keep_caches_of_others() {
+    let block = Box::new([1u64; 4]);
+    let address = &*block as *const _ as usize;
+    drop(block);
+    for _ in 0..10 {
+        thread::spawn(|| black_box(vec![0u8; 100])).join().unwrap();
+    }
+    // The exits only concern their own threads, this one still hands out its cached block
+    let block = Box::new([2u64; 4]);
+    assert_eq!(&*block as *const _ as usize, address);
+}
+
+#[test]
+fn test_fallbacks_are_counted() {
+    extern "C" fn alloc_on_exit(_: *mut libc::c_void) {
+        black_box(vec![0u8; 100]);
+    }
+    let before = BeneAlloc::fallback_count();
+    let mut key = 0;
+    assert_eq!(
+        unsafe { libc::pthread_key_create(&mut key, Some(alloc_on_exit)) },
+        0
+    );
+    // The destructors of pthread keys run after the ones of thread-locals, so this allocates
+    // after the heap of the thread is gone
+    thread::spawn(move || unsafe { libc::pthread_setspecific(key, 1 as *const libc::c_void) })
+        .join()
+        .unwrap();
+    unsafe { libc::pthread_key_delete(key) };
+    assert!(BeneAlloc::fallback_count() > before);
+}
+
+#[test]
+fn test_stats() {
+    let before = benemalloc::stats();
+    let boxes: Vec<Box<[u8; 100]>> = (0..10_000).map(|_| Box::new([0; 100])).collect();
+    let during = benemalloc::stats();
+    assert!(during.allocations >= before.allocations + boxes.len());
+    // Every box is a block of the 112 byte class
+    let class = during
+        .classes
+        .iter()
+        .find(|class| class.block_size == 112)
+        .unwrap();
+    assert!(class.current_bytes >= boxes.len() * 112);
+    assert!(during.peak_bytes >= during.current_bytes);
+    drop(boxes);
+    let after = benemalloc::stats();
+    assert!(after.frees >= during.frees + 10_000);
+    assert!(after.cache_hits > 0);
+}
+
+#[test]
+fn test_trim() {
+    // Large blocks stay in the cache of the thread when they are freed
+    let blocks: Vec<Vec<u8>> = (0..64).map(|_| vec![1; 1 << 20]).collect();
+    drop(blocks);
+    let before = benemalloc::stats();
+    benemalloc::trim();
+    let after = benemalloc::stats();
+    assert!(after.unmaps >= before.unmaps + 64);
+    // What is allocated afterwards is mapped anew
+    let blocks: Vec<Vec<u8>> = (0..64).map(|_| vec![2; 1 << 20]).collect();
+    assert!(blocks
+        .iter()
+        .all(|block| block.iter().all(|&byte| byte == 2)));
+}
+
+#[test]
+fn test_trim_abandoned() {
+    // The segments of the thread are abandoned with the blocks still in use when it exits, and
+    // emptied afterwards
+    let blocks: Vec<Box<[u8; 12_000]>> =
+        thread::spawn(|| (0..100).map(|_| Box::new([1; 12_000])).collect())
+            .join()
+            .unwrap();
+    drop(blocks);
+    let before = benemalloc::stats();
+    benemalloc::trim();
+    let after = benemalloc::stats();
+    assert!(after.unmaps >= before.unmaps + 4);
+}
+
+#[cfg(feature = "track_allocations")]
+#[test]
+fn test_trace_file() {
+    use benemalloc::trace::{Kind, Reader};
+    const SIZE: usize = 12_345;
+    // The trace is opened on the first allocation, so it is written by a child process running
+    // only this test
+    if std::env::var_os("BENEMALLOC_TEST_TRACE").is_some() {
+        let layout = Layout::from_size_align(SIZE, 8).unwrap();
+        unsafe { ALLOCATOR.dealloc(ALLOCATOR.alloc(layout), layout) };
+        ALLOCATOR.flush_trace();
+        return;
+    }
+    let path = std::env::temp_dir().join(format!("benemalloc-test-{}.trace", std::process::id()));
+    let output = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["test_trace_file", "--nocapture"])
+        .env("BENEMALLOC_TEST_TRACE", "1")
+        .env("BENEMALLOC_TRACK", &path)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let file = std::fs::File::open(&path).unwrap();
+    let records = Reader::new(std::io::BufReader::new(file))
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let alloc = records
+        .iter()
+        .position(|record| record.kind == Kind::Alloc && record.size == SIZE as u64)
+        .unwrap();
+    let free = records
+        .iter()
+        .position(|record| record.kind == Kind::Free && record.addr == records[alloc].addr)
+        .unwrap();
+    assert!(alloc < free);
+    assert_eq!(records[alloc].thread, records[free].thread);
+}
+
+#[cfg(feature = "track_allocations")]
+#[test]
+fn test_trace_directory() {
+    use benemalloc::trace::{Kind, Reader};
+    // Too large to be mapped, the allocation fails
+    const SIZE: usize = 1 << 62;
+    if std::env::var_os("BENEMALLOC_TEST_TRACE").is_some() {
+        let layout = Layout::from_size_align(SIZE, 8).unwrap();
+        assert!(unsafe { ALLOCATOR.alloc(layout) }.is_null());
+        ALLOCATOR.flush_trace();
+        return;
+    }
+    let run = |track: &std::path::Path| {
+        let child = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["test_trace_directory", "--nocapture"])
+            .env("BENEMALLOC_TEST_TRACE", "1")
+            .env("BENEMALLOC_TRACK", track)
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let pid = child.id();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        (pid, output.stderr)
+    };
+    let dir = std::env::temp_dir().join(format!("benemalloc-test-{}", std::process::id()));
+    std::fs::create_dir(&dir).unwrap();
+    let (pid, _) = run(&dir);
+    let path = dir.join(format!("benemalloc-{pid}.trace"));
+    let file = std::fs::File::open(&path).unwrap();
+    let records = Reader::new(std::io::BufReader::new(file))
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(!records.is_empty());
+    assert!(records
+        .iter()
+        .all(|record| record.kind != Kind::Alloc || record.addr != 0));
+    // A trace that can't be opened turns tracking off instead of writing to stderr
+    let (_, stderr) = run(&dir.join("missing").join("trace"));
+    assert!(stderr.is_empty());
+}
+
+#[cfg(feature = "heap_profile")]
+#[test]
+fn test_heap_profile() {
+    assert!(benemalloc::profile::sample_rate() > 0);
+    let mut before = Vec::new();
+    benemalloc::profile::write_pprof(&mut before).unwrap();
+    // 64 MiB in use is sampled about a hundred times with the default rate
+    let blocks: Vec<Vec<u8>> = (0..64 * 1024).map(|_| vec![1; 1024]).collect();
+    let mut profile = Vec::new();
+    benemalloc::profile::write_pprof(&mut profile).unwrap();
+    let contains = |needle: &[u8]| profile.windows(needle.len()).any(|window| window == needle);
+    assert!(contains(b"inuse_space"));
+    assert!(contains(b"alloc_objects"));
+    // The stack of this test is new
+    assert!(profile.len() > before.len());
+    drop(blocks);
+}
+
+#[cfg(feature = "leak_check")]
+#[test]
+fn test_leak_check() {
+    let checkpoint = benemalloc::leaks::checkpoint();
+    let mut request: Vec<String> = (0..1000).map(|i| i.to_string()).collect();
+    request.shrink_to_fit();
+    drop(request);
+    assert!(checkpoint.thread_leaks().is_empty());
+
+    let leaked = Box::leak(Box::new([0u8; 300]));
+    let report = checkpoint.thread_leaks();
+    assert_eq!(report.count(), 1);
+    assert_eq!(report.groups[0].size, 300);
+    unsafe { drop(Box::from_raw(leaked)) };
+    assert!(checkpoint.thread_leaks().is_empty());
+}
+
+#[cfg(feature = "hardened")]
+#[test]
+fn test_hardened() {
+    // Misuse aborts, so it happens in a child process running only this test
+    if let Ok(case) = std::env::var("BENEMALLOC_TEST_MISUSE") {
+        let layout = Layout::new::<[u8; 64]>();
+        unsafe {
+            let ptr = ALLOCATOR.alloc(layout);
+            match case.as_str() {
+                "double_free" => ALLOCATOR.dealloc(ptr, layout),
+                _ => ptr.add(layout.size()).write(!ptr.add(layout.size()).read()),
+            }
+            ALLOCATOR.dealloc(ptr, layout);
+        }
+        return;
+    }
+    for (case, diagnostic) in [
+        ("double_free", "double free"),
+        ("overflow", "overwrote the canary"),
+    ] {
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["test_hardened", "--nocapture"])
+            .env("BENEMALLOC_TEST_MISUSE", case)
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains(diagnostic));
+    }
+}
+
 #[test]
 fn test_tokio() {
     let rt = tokio::runtime::Runtime::new().unwrap();
@@ -1,4 +1,4 @@
-use benemalloc::BeneAlloc;
+use benemalloc::{BeneAlloc, FreeFill, Heap};
 use rand::prelude::SliceRandom;
 use rand::{thread_rng, Rng};
 use std::alloc::{Allocator, GlobalAlloc, Layout};
@@ -12,7 +12,7 @@ fn test_grow() {
     assert!(!ptr.is_null());
     let ptr = unsafe { allocator.realloc(ptr, layout, 100) };
     assert!(!ptr.is_null());
-    unsafe { allocator.dealloc(ptr, layout) };
+    unsafe { allocator.dealloc(ptr, Layout::from_size_align(100, 1).unwrap()) };
 }
 
 fn check_can_access(allocations: &Vec<(*mut u8, Layout)>) {
@@ -32,3 +32,344 @@ fn check_can_access(allocations: &Vec<(*mut u8, Layout)>) {
 fn dealloc<A: Allocator + GlobalAlloc>(allocator: &mut A, allocation: (*mut u8, Layout)) {
     unsafe { allocator.dealloc(allocation.0, allocation.1) };
 }
+
+#[test]
+fn test_builder_options() {
+    let allocator = BeneAlloc::builder()
+        .thread_cache_capacity(0)
+        .never_unmap(true)
+        .build();
+    let layout = Layout::from_size_align(2 << 20, 8).unwrap();
+    unsafe {
+        let ptr = allocator.alloc(layout);
+        ptr.write_bytes(1, layout.size());
+        allocator.dealloc(ptr, layout);
+        // Even without a thread cache, a mapping that is never unmapped is kept for reuse
+        assert_eq!(allocator.alloc(layout), ptr);
+        allocator.dealloc(ptr, layout);
+    }
+}
+
+#[test]
+fn test_verbose_config() {
+    // The environment is read on the first allocation, so the configuration is printed by a
+    // child process running only this test
+    if std::env::var_os("BENEMALLOC_TEST_VERBOSE").is_some() {
+        let allocator = BeneAlloc::builder().thread_cache_capacity(7).build();
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        unsafe { allocator.dealloc(allocator.alloc(layout), layout) };
+        return;
+    }
+    let output = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["test_verbose_config", "--nocapture"])
+        .env("BENEMALLOC_TEST_VERBOSE", "1")
+        .env("BENEMALLOC_VERBOSE", "1")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("benemalloc: thread_cache_capacity=7 "));
+}
+
+#[test]
+fn test_no_thread_cache() {
+    let allocator = BeneAlloc::builder().thread_cache_capacity(0).build();
+    // A class nothing else in the thread allocates, so its slab starts out empty
+    let layout = Layout::from_size_align(3000, 8).unwrap();
+    std::thread::spawn(move || unsafe {
+        let ptr = allocator.alloc(layout);
+        allocator.dealloc(ptr, layout);
+        // Nothing of the refill was cached, so the block comes back from the slab
+        assert_eq!(allocator.alloc(layout), ptr);
+        allocator.dealloc(ptr, layout);
+    })
+    .join()
+    .unwrap();
+}
+
+#[test]
+fn test_trim_never_unmap() {
+    let allocator = BeneAlloc::builder().never_unmap(true).build();
+    let layout = Layout::from_size_align(64 << 10, 8).unwrap();
+    std::thread::spawn(move || unsafe {
+        let ptr = allocator.alloc(layout);
+        ptr.write_bytes(1, layout.size());
+        allocator.dealloc(ptr, layout);
+        // The cached block gives back its pages but stays mapped, and is zero when reused
+        benemalloc::trim();
+        assert_eq!(allocator.alloc_zeroed(layout), ptr);
+        assert!(std::slice::from_raw_parts(ptr, layout.size())
+            .iter()
+            .all(|&byte| byte == 0));
+        allocator.dealloc(ptr, layout);
+    })
+    .join()
+    .unwrap();
+}
+
+#[test]
+fn test_allocator_api() {
+    let allocator = BeneAlloc::new();
+    let mut numbers: Vec<u64, &BeneAlloc> = Vec::new_in(&allocator);
+    numbers.extend(0..10_000);
+    numbers.shrink_to(100);
+    assert!(numbers.iter().copied().eq(0..10_000));
+    numbers.truncate(10);
+    numbers.shrink_to_fit();
+    assert!(numbers.iter().copied().eq(0..10));
+
+    // A block reports all of its size class
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    let block = allocator.allocate(layout).unwrap();
+    assert_eq!(block.len(), BeneAlloc::usable_size(layout));
+    unsafe { allocator.deallocate(block.cast(), layout) };
+
+    let zeroed = allocator.allocate_zeroed(layout).unwrap();
+    let grown = Layout::from_size_align(1 << 20, 4096).unwrap();
+    let grown_block = unsafe { allocator.grow_zeroed(zeroed.cast(), layout, grown) }.unwrap();
+    assert!(unsafe { grown_block.as_ref() }
+        .iter()
+        .all(|&byte| byte == 0));
+    unsafe { allocator.deallocate(grown_block.cast(), grown) };
+
+    let boxed = Box::new_in([7u8; 3000], &allocator);
+    assert!(boxed.iter().all(|&byte| byte == 7));
+}
+
+#[test]
+fn test_allocator_api_next_to_global() {
+    // Shares the heap of this thread with the global allocator, except for what it never unmaps
+    let allocator = BeneAlloc::builder()
+        .never_unmap(true)
+        .free_fill(FreeFill::Poison)
+        .build();
+    let mut custom: Vec<u8, &BeneAlloc> = Vec::with_capacity_in(100 << 10, &allocator);
+    custom.resize(100 << 10, 1);
+    let custom_ptr = custom.as_ptr();
+    let global = vec![2u8; 100 << 10];
+    drop(custom);
+    // The block stays with the allocator that never unmaps it
+    let global_again = vec![3u8; 100 << 10];
+    assert_ne!(global_again.as_ptr(), custom_ptr);
+    let custom: Vec<u8, &BeneAlloc> = Vec::with_capacity_in(100 << 10, &allocator);
+    assert_eq!(custom.as_ptr(), custom_ptr);
+
+    // So do small blocks, which come back filled the way the allocator asks for
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let small = allocator.allocate(layout).unwrap().cast::<u8>();
+    unsafe { allocator.deallocate(small, layout) };
+    let global_small = Box::new([5u8; 64]);
+    assert_ne!(
+        &*global_small as *const [u8; 64] as *const u8,
+        small.as_ptr()
+    );
+    let small_again = allocator.allocate(layout).unwrap().cast::<u8>();
+    assert_eq!(small_again, small);
+    assert!((8..layout.size()).all(|i| unsafe { *small.as_ptr().add(i) } == 0xde));
+    unsafe { allocator.deallocate(small_again, layout) };
+    assert!(global.iter().all(|&byte| byte == 2));
+    assert!(global_again.iter().all(|&byte| byte == 3));
+}
+
+#[test]
+fn test_free_fill() {
+    let allocator = BeneAlloc::builder().free_fill(FreeFill::Poison).build();
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    unsafe {
+        let ptr = allocator.alloc(layout);
+        ptr.write_bytes(0xaa, layout.size());
+        allocator.dealloc(ptr, layout);
+        // The block comes right back, with the link to the next free block at its start
+        assert_eq!(allocator.alloc(layout), ptr);
+        assert!((8..layout.size()).all(|i| *ptr.add(i) == 0xde));
+        allocator.dealloc(ptr, layout);
+    }
+}
+
+#[test]
+fn test_randomize_reuse() {
+    let allocator = BeneAlloc::builder().randomize_reuse(true).build();
+    let layout = Layout::from_size_align(48, 8).unwrap();
+    let freed: Vec<*mut u8> = (0..64)
+        .map(|_| unsafe { allocator.alloc(layout) })
+        .collect();
+    for &ptr in &freed {
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+    let reused: Vec<*mut u8> = (0..64)
+        .map(|_| unsafe { allocator.alloc(layout) })
+        .collect();
+    // Without randomization the blocks would come back in the reverse order they were freed in
+    assert!(reused.iter().ne(freed.iter().rev()));
+    for ptr in reused {
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+}
+
+#[test]
+fn test_heap_foreign_block() {
+    // Freeing a block the heap doesn't own aborts, so it happens in a child process running only
+    // this test
+    let layout = Layout::from_size_align(1 << 20, 8).unwrap();
+    if std::env::var_os("BENEMALLOC_TEST_FOREIGN").is_some() {
+        let heap = Heap::new();
+        let block = vec![1u8; layout.size()];
+        unsafe { heap.dealloc(block.as_ptr() as *mut u8, layout) };
+        // The block is still mapped
+        assert!(block.iter().all(|&byte| byte == 1));
+        return;
+    }
+    let output = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["test_heap_foreign_block", "--nocapture"])
+        .env("BENEMALLOC_TEST_FOREIGN", "1")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is not a block of this heap"));
+}
+
+#[test]
+fn test_heap_destroy() {
+    let heap = Heap::new();
+    let layouts = [
+        Layout::from_size_align(24, 8).unwrap(),
+        Layout::from_size_align(3000, 64).unwrap(),
+        Layout::from_size_align(1 << 20, 1 << 16).unwrap(),
+    ];
+    let mut blocks = Vec::new();
+    for i in 0..3000 {
+        let layout = layouts[i % layouts.len()];
+        let ptr = unsafe { heap.alloc(layout) };
+        assert!(!ptr.is_null());
+        assert!((ptr as usize).is_multiple_of(layout.align()));
+        unsafe { ptr.write_bytes(i as u8, layout.size()) };
+        // Free some of the blocks, the rest goes away with the heap
+        if i % 4 == 0 {
+            unsafe { heap.dealloc(ptr, layout) };
+        } else {
+            blocks.push((i, ptr, layout));
+        }
+    }
+    for &(i, ptr, layout) in &blocks {
+        let contents = unsafe { std::slice::from_raw_parts(ptr, layout.size()) };
+        assert!(contents.iter().all(|&byte| byte == i as u8));
+    }
+
+    let mut numbers: Vec<u64, &Heap> = Vec::new_in(&heap);
+    numbers.extend(0..10_000);
+    assert!(numbers.iter().copied().eq(0..10_000));
+    // Nothing has to be freed before the heap is destroyed
+    std::mem::forget(numbers);
+    heap.destroy();
+}
+
+// Guard pages keep blocks of the hardened mode apart
+#[cfg(not(feature = "hardened"))]
+#[test]
+fn test_never_unmap_bumps_blocks() {
+    let allocator = BeneAlloc::builder().never_unmap(true).build();
+    let layout = Layout::from_size_align(40 << 10, 8).unwrap();
+    let blocks: Vec<*mut u8> = (0..1000)
+        .map(|_| unsafe { allocator.alloc(layout) })
+        .collect();
+    // Blocks are bumped from a shared region instead of being mapped one by one
+    let adjacent = blocks
+        .windows(2)
+        .filter(|pair| pair[1] as usize == pair[0] as usize + layout.size())
+        .count();
+    assert!(adjacent > 990);
+    for block in blocks {
+        unsafe { allocator.dealloc(block, layout) };
+    }
+}
+
+#[test]
+fn test_grow_within_class() {
+    let allocator = BeneAlloc::new();
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    unsafe {
+        let ptr = allocator.alloc(layout);
+        // 100 and 104 bytes share a size class, even with the canary of the hardened mode after
+        // them, so the block is already large enough
+        let grown = allocator.realloc(ptr, layout, 104);
+        assert_eq!(ptr, grown);
+        allocator.dealloc(grown, Layout::from_size_align(104, 8).unwrap());
+    }
+}
+
+#[test]
+fn test_grow_large_keeps_contents() {
+    let allocator = BeneAlloc::new();
+    let layout = Layout::from_size_align(1 << 20, 8).unwrap();
+    unsafe {
+        let ptr = allocator.alloc(layout);
+        for i in 0..layout.size() {
+            *ptr.add(i) = i as u8;
+        }
+        let grown = allocator.realloc(ptr, layout, 80 << 20);
+        assert!(!grown.is_null());
+        for i in 0..layout.size() {
+            assert_eq!(*grown.add(i), i as u8);
+        }
+        allocator.dealloc(grown, Layout::from_size_align(80 << 20, 8).unwrap());
+    }
+}
+
+#[test]
+fn test_usable_size_is_backed() {
+    let allocator = BeneAlloc::new();
+    for size in [1, 9, 100, 5000, 20_000, 1 << 20] {
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        let usable = BeneAlloc::usable_size(layout);
+        assert!(usable >= size);
+        unsafe {
+            // The tail past the requested size belongs to the block, also once it is recycled
+            let ptr = allocator.alloc(layout);
+            ptr.write_bytes(0xaa, usable);
+            allocator.dealloc(ptr, layout);
+            let ptr = allocator.alloc(layout);
+            ptr.write_bytes(0xbb, usable);
+            allocator.dealloc(ptr, layout);
+        }
+    }
+}
+
+#[test]
+fn test_trace_roundtrip() {
+    use benemalloc::trace::{Action, Header, Kind, Reader, Record};
+
+    let records = [
+        Record {
+            timestamp_ns: 1,
+            thread: 1,
+            kind: Kind::Alloc,
+            action: Action::Slab,
+            addr: 0x1000,
+            size: 24,
+            new_addr: 0,
+            callsite: 0xdead_beef,
+        },
+        Record {
+            timestamp_ns: 2,
+            thread: 2,
+            kind: Kind::Resize,
+            action: Action::None,
+            addr: 0x1000,
+            size: 4096,
+            new_addr: 0x2000,
+            callsite: 0,
+        },
+    ];
+    let mut trace = Header::current().to_bytes().to_vec();
+    for record in &records {
+        trace.extend_from_slice(&record.to_bytes());
+    }
+    // A partial record at the end, as left behind by a crash
+    trace.extend_from_slice(&[1, 2, 3]);
+
+    let reader = Reader::new(&trace[..]).unwrap();
+    assert_eq!(reader.header(), Header::current());
+    let decoded: Vec<Record> = reader.map(Result::unwrap).collect();
+    assert_eq!(decoded, records);
+    assert!(Reader::new(&b"not a trace at all"[..]).is_err());
+}